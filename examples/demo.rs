@@ -20,7 +20,10 @@ fn main() {
         let mut string = String::new();
         file.read_to_string(&mut string).unwrap();
         let evaluator = aariba::parse_rule(&string).unwrap();
-        evaluator.evaluate(&mut global_variables).unwrap();
-        println!("Evaluation of rules {}\n => {:#?}", string, global_variables);
+        let result = evaluator.evaluate(&mut global_variables).unwrap();
+        println!("Evaluation of rules {}\n => {:#?}", string, aariba::expressions::sorted_snapshot(&global_variables));
+        if let Some(value) = result {
+            println!("Result: {}", value);
+        }
     }
 }
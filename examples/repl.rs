@@ -2,26 +2,43 @@ extern crate aariba;
 
 use std::io::{self,BufRead};
 use std::collections::HashMap;
-use std::fmt::Write;
+
+use aariba::expressions::sorted_snapshot;
+use aariba::rules::RulesEvaluator;
 
 fn main() {
     let stdin = io::stdin();
-    let mut accumulated_rules = String::new();
+    let mut rules = RulesEvaluator::new(Vec::new());
+    let mut pending = String::new();
     for line in stdin.lock().lines().filter_map(|l| l.ok()) {
         match line.trim() {
-            "clear;" => accumulated_rules.clear(),
+            "clear;" => {
+                rules = RulesEvaluator::new(Vec::new());
+                pending.clear();
+            }
             _ => {
-                let mut new_rules = String::new();
-                write!(new_rules, "{}{}\n", accumulated_rules, line).unwrap();
-                println!("Evaluating the following rules:\n{}", new_rules);
-                let res = aariba::parse_rule(&new_rules);
+                pending.push_str(&line);
+                pending.push('\n');
+                // Keep reading lines until the instruction is complete
+                if pending.trim().is_empty() || !aariba::is_complete(&pending) {
+                    continue;
+                }
+                let res = aariba::parse_instruction(&pending);
+                pending.clear();
                 match res {
-                    Ok(evaluator) => {
+                    Ok(instructions) => {
+                        let mut new_rules = rules.clone();
+                        for instruction in instructions {
+                            new_rules.push(instruction);
+                        }
                         let mut global_variables = HashMap::new();
-                        match evaluator.evaluate(&mut global_variables) {
-                            Ok(()) => {
-                                println!("Global variables: {:#?}", global_variables);
-                                accumulated_rules = new_rules;
+                        match new_rules.evaluate(&mut global_variables) {
+                            Ok(result) => {
+                                println!("Global variables: {:#?}", sorted_snapshot(&global_variables));
+                                if let Some(value) = result {
+                                    println!("Result: {}", value);
+                                }
+                                rules = new_rules;
                             }
                             Err(e) => {
                                 println!("Evaluation error: {:?}", e);
@@ -1,4 +1,7 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 
 use rand;
 
@@ -10,6 +13,18 @@ pub trait Store {
     ///
     /// Returns the old value, if any
     fn set_attribute(&mut self, var: &str, value: f64) -> Result<Option<f64>,()>;
+    /// Get the value "var" had "steps_back" evaluations ago
+    ///
+    /// Stores do not keep any history by default
+    fn get_history(&self, _var: &str, _steps_back: usize) -> Option<f64> {
+        None
+    }
+    /// Names of all the variables in the store, used by sum_where
+    ///
+    /// Stores cannot be enumerated by default
+    fn names(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
 impl Store for HashMap<String,f64> {
@@ -20,6 +35,10 @@ impl Store for HashMap<String,f64> {
     fn set_attribute(&mut self, var: &str, value: f64) -> Result<Option<f64>,()> {
         Ok(self.insert(var.into(), value))
     }
+
+    fn names(&self) -> Vec<String> {
+        self.keys().cloned().collect()
+    }
 }
 
 impl Store for () {
@@ -32,6 +51,63 @@ impl Store for () {
     }
 }
 
+/// Variables of a store with their values, sorted by name
+///
+/// Only stores which can be enumerated (see Store::names) give a non empty snapshot.
+pub fn sorted_snapshot<S: Store + ?Sized>(store: &S) -> Vec<(String,f64)> {
+    let mut names = store.names();
+    names.sort();
+    names.dedup();
+    names.into_iter().filter_map(|name| {
+        store.get_attribute(&name).map(|value| (name, value))
+    }).collect()
+}
+
+/// Store looking variables up in several layers, such as per-entity overrides then defaults
+///
+/// Variables are read from the first layer which has them, and always written to the top layer.
+pub struct Layered<'a> {
+    top: &'a mut Store,
+    lower: Vec<&'a Store>,
+}
+
+impl <'a> Layered<'a> {
+    pub fn new(top: &'a mut Store, lower: Vec<&'a Store>) -> Layered<'a> {
+        Layered {
+            top: top,
+            lower: lower,
+        }
+    }
+}
+
+impl <'a> Store for Layered<'a> {
+    fn get_attribute(&self, var: &str) -> Option<f64> {
+        self.top.get_attribute(var).or_else(|| {
+            self.lower.iter().filter_map(|layer| layer.get_attribute(var)).next()
+        })
+    }
+
+    fn set_attribute(&mut self, var: &str, value: f64) -> Result<Option<f64>,()> {
+        self.top.set_attribute(var, value)
+    }
+
+    fn get_history(&self, var: &str, steps_back: usize) -> Option<f64> {
+        self.top.get_history(var, steps_back).or_else(|| {
+            self.lower.iter().filter_map(|layer| layer.get_history(var, steps_back)).next()
+        })
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = self.top.names();
+        for layer in self.lower.iter() {
+            names.extend(layer.names());
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
 // Postfixed expression notation
 // member1 member2 operator to do a conventional member1 operator member2
 // A member can itself be an expression
@@ -44,6 +120,26 @@ pub enum ExpressionMember {
     Op(Operator),
     Constant(f64),
     Variable(Variable),
+    /// Past value of the variable, the number of steps back is popped from the stack
+    Previous(Variable),
+    /// Sum of the global variables whose name starts with the given prefix
+    SumGlobals(String),
+    /// Value of the variable, or the value popped from the stack if it is not set
+    VariableOr(Variable),
+}
+
+impl ExpressionMember {
+    // Number of values popped from the stack by the member
+    fn nb_operands(&self) -> usize {
+        match *self {
+            ExpressionMember::Constant(_) | ExpressionMember::Variable(_) => 0,
+            ExpressionMember::SumGlobals(_) => 0,
+            ExpressionMember::Previous(_) | ExpressionMember::VariableOr(_) => 1,
+            ExpressionMember::Op(Operator::Unary(_)) => 1,
+            ExpressionMember::Op(Operator::Binary(_)) => 2,
+            ExpressionMember::Op(Operator::Variadic(_, nb_operands)) => nb_operands,
+        }
+    }
 }
 
 #[derive(Clone,Debug)]
@@ -58,6 +154,7 @@ impl Variable {
     }
 }
 
+// "$name" is a global variable, whatever the global sigil of ParseOptions
 impl From<String> for Variable {
     fn from(mut name: String) -> Variable {
         let local;
@@ -79,24 +176,128 @@ impl From<String> for Variable {
 pub enum Operator {
     Unary(UnaryOperator),
     Binary(BinaryOperator),
+    /// Operator applied to the given number of operands
+    Variadic(VariadicOperator, usize),
 }
 
 impl Operator {
+    /// Whether the operator always gives the same result for the same operands
+    pub fn is_pure(self) -> bool {
+        match self {
+            Operator::Binary(BinaryOperator::Rand) => false,
+            _ => true,
+        }
+    }
+
+    // Checks that enough operands are available on the stack
+    fn check_arity(self, available: usize) -> Result<(),ExpressionError> {
+        let expected = match self {
+            Operator::Unary(_) => 1,
+            Operator::Binary(_) => 2,
+            Operator::Variadic(_, nb_operands) => nb_operands,
+        };
+        if expected == 0 {
+            return Err(InvalidExpression(format!("No member for operator {:?}", self).into()));
+        }
+        if available < expected {
+            return Err(WrongArity { operator: self, expected: expected, found: available });
+        }
+        Ok(())
+    }
+
+    // Whether the operator converts its operands to integers
+    fn uses_integer_parts(self) -> bool {
+        match self {
+            Operator::Unary(UnaryOperator::IsPow2) |
+            Operator::Unary(UnaryOperator::Popcount) |
+            Operator::Binary(BinaryOperator::Gcd) |
+            Operator::Binary(BinaryOperator::Lcm) |
+            Operator::Binary(BinaryOperator::Every) |
+            Operator::Binary(BinaryOperator::BitAnd) |
+            Operator::Binary(BinaryOperator::BitOr) |
+            Operator::Binary(BinaryOperator::BitXor) |
+            Operator::Binary(BinaryOperator::Shl) |
+            Operator::Binary(BinaryOperator::Shr) => true,
+            _ => false,
+        }
+    }
+
     fn apply(self, stack: &mut Vec<f64>) -> Result<f64,ExpressionError> {
+        try!(self.check_arity(stack.len()));
         match self {
             Operator::Unary(op) => {
-                let operand = try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for operator {:?}", self))));
+                let operand = stack.pop().unwrap();
                 Ok(op.apply(operand))
             }
             Operator::Binary(op) => {
-                let rhs = try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for operator {:?}", self))));
-                let lhs = try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for operator {:?}", self))));
+                let rhs = stack.pop().unwrap();
+                let lhs = stack.pop().unwrap();
+                if let BinaryOperator::Step = op {
+                    if rhs == 0.0 {
+                        return Err(InvalidExpression("step size of step(x, s) is 0".into()));
+                    }
+                }
                 Ok(op.apply(lhs,rhs))
             },
+            Operator::Variadic(op, nb_operands) => {
+                let start = stack.len() - nb_operands;
+                let result = op.apply(&stack[start..]);
+                stack.truncate(start);
+                Ok(result)
+            }
+        }
+    }
+
+    // Same as apply, on intervals containing the possible values of each operand
+    fn apply_interval(self, stack: &mut Vec<Interval>) -> Result<Interval,ExpressionError> {
+        try!(self.check_arity(stack.len()));
+        match self {
+            Operator::Unary(op) => {
+                let operand = stack.pop().unwrap();
+                Ok(op.apply_interval(operand))
+            }
+            Operator::Binary(op) => {
+                let rhs = stack.pop().unwrap();
+                let lhs = stack.pop().unwrap();
+                Ok(op.apply_interval(lhs, rhs))
+            },
+            Operator::Variadic(op, nb_operands) => {
+                let start = stack.len() - nb_operands;
+                let result = op.apply_interval(&stack[start..]);
+                stack.truncate(start);
+                Ok(result)
+            }
         }
     }
 }
 
+// Lowest and highest possible values
+type Interval = (f64, f64);
+
+const UNBOUNDED: Interval = (::std::f64::NEG_INFINITY, ::std::f64::INFINITY);
+
+// Smallest interval containing all the values, unbounded if one of them is undefined (such as
+// 0 * inf)
+fn hull(values: &[f64]) -> Interval {
+    if values.iter().any(|x| x.is_nan()) {
+        return UNBOUNDED;
+    }
+    values.iter().fold((::std::f64::INFINITY, ::std::f64::NEG_INFINITY), |(low, high), &x| {
+        (low.min(x), high.max(x))
+    })
+}
+
+fn interval_multiply(lhs: Interval, rhs: Interval) -> Interval {
+    hull(&[lhs.0 * rhs.0, lhs.0 * rhs.1, lhs.1 * rhs.0, lhs.1 * rhs.1])
+}
+
+fn interval_divide(lhs: Interval, rhs: Interval) -> Interval {
+    if rhs.0 <= 0.0 && rhs.1 >= 0.0 {
+        return UNBOUNDED;
+    }
+    interval_multiply(lhs, (1.0 / rhs.1, 1.0 / rhs.0))
+}
+
 #[derive(Clone,Copy,Debug)]
 pub enum BinaryOperator {
     Plus,
@@ -107,9 +308,62 @@ pub enum BinaryOperator {
     Min,
     Max,
     Rand,
+    Monus,
+    Gcd,
+    Lcm,
+    /// 1.0 if the integer part of lhs is a multiple of the integer part of rhs, 0.0 otherwise
+    /// (NaN when rhs is 0)
+    Every,
+    /// Rounds lhs to the nearest multiple of rhs (NaN when rhs is 0)
+    Step,
+    /// Bitwise operations on the operands truncated to integers ("6.7 & -3.2" is "6 & -3")
+    BitAnd,
+    BitOr,
+    BitXor,
+    /// Arithmetic shifts of the truncated lhs by the truncated rhs (NaN when rhs is negative)
+    ///
+    /// Shifting left by 64 or more gives 0, shifting right by 64 or more gives 0 or -1 depending
+    /// on the sign of lhs.
+    Shl,
+    Shr,
+    /// Length of the hypotenuse of a right triangle with sides lhs and rhs
+    Hypot,
+    /// Absolute value of lhs - rhs
+    AbsDiff,
+    /// copysign(magnitude, sign) is the absolute value of magnitude with the sign of sign
+    CopySign,
+    /// triangle(t, period) rises linearly from 0 at multiples of period to 1 half a period
+    /// later, then falls back to 0 (NaN if period is not positive)
+    Triangle,
+    /// sawtooth(t, period) rises linearly from 0 at multiples of period towards 1, in [0, 1)
+    /// (NaN if period is not positive)
+    Sawtooth,
+    /// wrap(x, n) is x modulo n, with the sign of n (wrap(-1, 3) is 2, NaN when n is 0)
+    Wrap,
 }
 
 impl BinaryOperator {
+    fn apply_interval(self, lhs: Interval, rhs: Interval) -> Interval {
+        match self {
+            BinaryOperator::Plus => (lhs.0 + rhs.0, lhs.1 + rhs.1),
+            BinaryOperator::Minus => (lhs.0 - rhs.1, lhs.1 - rhs.0),
+            BinaryOperator::Multiply => interval_multiply(lhs, rhs),
+            BinaryOperator::Divide => interval_divide(lhs, rhs),
+            // Monotonic in both operands for positive bases only
+            BinaryOperator::Pow if lhs.0 > 0.0 => {
+                hull(&[lhs.0.powf(rhs.0), lhs.0.powf(rhs.1), lhs.1.powf(rhs.0), lhs.1.powf(rhs.1)])
+            }
+            BinaryOperator::Pow => UNBOUNDED,
+            BinaryOperator::Min => (lhs.0.min(rhs.0), lhs.1.min(rhs.1)),
+            BinaryOperator::Max => (lhs.0.max(rhs.0), lhs.1.max(rhs.1)),
+            BinaryOperator::Rand => (lhs.0.min(rhs.0), lhs.1.max(rhs.1)),
+            BinaryOperator::Monus => ((lhs.0 - rhs.1).max(0.0), (lhs.1 - rhs.0).max(0.0)),
+            BinaryOperator::Gcd | BinaryOperator::Lcm => (0.0, ::std::f64::INFINITY),
+            BinaryOperator::Every | BinaryOperator::Triangle | BinaryOperator::Sawtooth => (0.0, 1.0),
+            _ => UNBOUNDED,
+        }
+    }
+
     fn apply(self, lhs: f64, rhs: f64) -> f64 {
         match self {
             BinaryOperator::Plus => lhs + rhs,
@@ -117,13 +371,211 @@ impl BinaryOperator {
             BinaryOperator::Multiply => lhs * rhs,
             BinaryOperator::Divide => lhs / rhs,
             BinaryOperator::Pow => lhs.powf(rhs),
-            BinaryOperator::Min => if lhs < rhs {lhs} else {rhs},
-            BinaryOperator::Max => if lhs > rhs {lhs} else {rhs},
+            // NaN operands are ignored whatever their position, like f64::min and f64::max
+            BinaryOperator::Min => lhs.min(rhs),
+            BinaryOperator::Max => lhs.max(rhs),
             BinaryOperator::Rand => {
                 let (min,max) = if lhs < rhs {(lhs,rhs)} else {(rhs,lhs)};
                 let rand: f64 = rand::random();
                 min + rand * (max - min)
             }
+            BinaryOperator::Monus => (lhs - rhs).max(0.0),
+            BinaryOperator::Gcd => gcd(integer_part(lhs), integer_part(rhs)) as f64,
+            BinaryOperator::Lcm => lcm(integer_part(lhs), integer_part(rhs)),
+            BinaryOperator::Every => every(lhs, rhs),
+            BinaryOperator::Step => step(lhs, rhs),
+            BinaryOperator::BitAnd => (truncate(lhs) & truncate(rhs)) as f64,
+            BinaryOperator::BitOr => (truncate(lhs) | truncate(rhs)) as f64,
+            BinaryOperator::BitXor => (truncate(lhs) ^ truncate(rhs)) as f64,
+            BinaryOperator::Shl => shift(lhs, rhs, true),
+            BinaryOperator::Shr => shift(lhs, rhs, false),
+            BinaryOperator::Hypot => lhs.hypot(rhs),
+            BinaryOperator::AbsDiff => (lhs - rhs).abs(),
+            BinaryOperator::CopySign => lhs.copysign(rhs),
+            BinaryOperator::Triangle => triangle(lhs, rhs),
+            BinaryOperator::Sawtooth => sawtooth(lhs, rhs),
+            BinaryOperator::Wrap => wrap(lhs, rhs),
+        }
+    }
+}
+
+// Hash of the bits of value (the splitmix64 finalizer), scaled to [0, 1)
+fn noise(value: f64) -> f64 {
+    let mut hash = value.to_bits();
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash = hash ^ (hash >> 31);
+    // Keeps the 53 bits which fit in the mantissa
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+fn every(counter: f64, n: f64) -> f64 {
+    let n = integer_part(n);
+    if n == 0 {
+        return ::std::f64::NAN;
+    }
+    if integer_part(counter) % n == 0 {1.0} else {0.0}
+}
+
+fn step(value: f64, step: f64) -> f64 {
+    if step == 0.0 {
+        return ::std::f64::NAN;
+    }
+    (value / step).round() * step
+}
+
+fn shift(value: f64, count: f64, left: bool) -> f64 {
+    let (value, count) = (truncate(value), truncate(count));
+    if count < 0 {
+        return ::std::f64::NAN;
+    }
+    let result = match (left, count) {
+        (true, count) if count >= 64 => 0,
+        (true, count) => value << count,
+        // Right shifts by 63 already fill every bit with the sign
+        (false, count) => value >> count.min(63),
+    };
+    result as f64
+}
+
+fn wrap(value: f64, n: f64) -> f64 {
+    if n == 0.0 {
+        return ::std::f64::NAN;
+    }
+    let result = value - n * (value / n).floor();
+    // Rounding may give exactly n for tiny negative values
+    if result == n {0.0} else {result}
+}
+
+fn sawtooth(t: f64, period: f64) -> f64 {
+    if !(period > 0.0) {
+        return ::std::f64::NAN;
+    }
+    let phase = t / period;
+    // Rounding may give exactly 1 for tiny negative phases
+    let value = phase - phase.floor();
+    if value < 1.0 {value} else {0.0}
+}
+
+fn triangle(t: f64, period: f64) -> f64 {
+    1.0 - (2.0 * sawtooth(t, period) - 1.0).abs()
+}
+
+// Whether the integer part of value can be converted to an i64 without saturating
+fn fits_in_i64(value: f64) -> bool {
+    // i64::MIN is exactly representable, but i64::MAX is rounded up to 2^63
+    value.is_finite() && value.trunc() >= ::std::i64::MIN as f64 && value.trunc() < ::std::i64::MAX as f64
+}
+
+// Integer part of a number, keeping its sign, for bitwise operators
+fn truncate(value: f64) -> i64 {
+    value.trunc() as i64
+}
+
+// Absolute value of the integer part of a number, for integer functions
+fn integer_part(value: f64) -> i64 {
+    value.trunc().abs() as i64
+}
+
+// gcd(0, 0) is 0
+fn gcd(mut a: i64, mut b: i64) -> i64 {
+    while b != 0 {
+        let remainder = a % b;
+        a = b;
+        b = remainder;
+    }
+    a
+}
+
+// lcm(0, x) is 0, computed as a float to avoid overflows
+fn lcm(a: i64, b: i64) -> f64 {
+    if a == 0 || b == 0 {
+        return 0.0;
+    }
+    (a / gcd(a, b)) as f64 * b as f64
+}
+
+#[derive(Clone,Copy,Debug)]
+pub enum VariadicOperator {
+    Min,
+    Max,
+    /// lerp(a, b, t) is a + (b - a) * t, t is not clamped so values outside of [0, 1]
+    /// extrapolate (NaN if not given exactly 3 operands)
+    Lerp,
+    /// smoothstep(edge0, edge1, x) eases from 0 to 1 while x goes from edge0 to edge1 (if both
+    /// edges are equal, it is 0 below them and 1 otherwise)
+    Smoothstep,
+    /// dot(x1, y1, x2, y2) is x1 * x2 + y1 * y2, the first half of the operands being the first
+    /// vector (NaN for an odd number of operands)
+    Dot,
+    /// Arithmetic mean of the operands
+    Avg,
+    /// poly(x, c0, c1, c2...) is c0 + c1 * x + c2 * x^2... (x alone gives 0)
+    Poly,
+}
+
+impl VariadicOperator {
+    fn apply_interval(self, operands: &[Interval]) -> Interval {
+        let (first, rest) = (operands[0], &operands[1..]);
+        match self {
+            VariadicOperator::Min => rest.iter().fold(first, |acc, x| (acc.0.min(x.0), acc.1.min(x.1))),
+            VariadicOperator::Max => rest.iter().fold(first, |acc, x| (acc.0.max(x.0), acc.1.max(x.1))),
+            VariadicOperator::Lerp if operands.len() == 3 => {
+                let (a, b, t) = (operands[0], operands[1], operands[2]);
+                let difference = BinaryOperator::Minus.apply_interval(b, a);
+                BinaryOperator::Plus.apply_interval(a, interval_multiply(difference, t))
+            }
+            VariadicOperator::Smoothstep => (0.0, 1.0),
+            VariadicOperator::Dot if operands.len() % 2 == 0 => {
+                let (lhs, rhs) = operands.split_at(operands.len() / 2);
+                lhs.iter().zip(rhs.iter()).fold((0.0, 0.0), |acc, (&x, &y)| {
+                    BinaryOperator::Plus.apply_interval(acc, interval_multiply(x, y))
+                })
+            }
+            VariadicOperator::Avg => {
+                let sum = rest.iter().fold(first, |acc, &x| BinaryOperator::Plus.apply_interval(acc, x));
+                let count = operands.len() as f64;
+                (sum.0 / count, sum.1 / count)
+            }
+            _ => UNBOUNDED,
+        }
+    }
+
+
+    // operands is never empty
+    fn apply(self, operands: &[f64]) -> f64 {
+        let (first, rest) = (operands[0], &operands[1..]);
+        match self {
+            VariadicOperator::Min => rest.iter().fold(first, |acc, &x| acc.min(x)),
+            VariadicOperator::Max => rest.iter().fold(first, |acc, &x| acc.max(x)),
+            VariadicOperator::Lerp => {
+                if operands.len() != 3 {
+                    return ::std::f64::NAN;
+                }
+                let (a, b, t) = (operands[0], operands[1], operands[2]);
+                a + (b - a) * t
+            }
+            VariadicOperator::Smoothstep => {
+                if operands.len() != 3 {
+                    return ::std::f64::NAN;
+                }
+                let (edge0, edge1, x) = (operands[0], operands[1], operands[2]);
+                if edge0 == edge1 {
+                    return if x < edge0 {0.0} else {1.0};
+                }
+                let t = ((x - edge0) / (edge1 - edge0)).max(0.0).min(1.0);
+                t * t * (3.0 - 2.0 * t)
+            }
+            VariadicOperator::Dot => {
+                if operands.len() % 2 != 0 {
+                    return ::std::f64::NAN;
+                }
+                let (lhs, rhs) = operands.split_at(operands.len() / 2);
+                lhs.iter().zip(rhs.iter()).fold(0.0, |acc, (x, y)| acc + x * y)
+            }
+            VariadicOperator::Avg => operands.iter().fold(0.0, |acc, x| acc + x) / operands.len() as f64,
+            // Horner's method, from the highest degree coefficient
+            VariadicOperator::Poly => rest.iter().rev().fold(0.0, |acc, &c| acc * first + c),
         }
     }
 }
@@ -133,27 +585,162 @@ pub enum UnaryOperator {
     Minus,
     Sin,
     Cos,
+    Exp,
+    Exp2,
+    /// Fractional part, with the sign of the operand (frac(-2.75) is -0.75)
+    Fract,
+    /// 1.0 if the integer part of the operand is a power of two, 0.0 otherwise
+    /// (fractions are truncated and negatives use their absolute value, so is_pow2(-8.5) is 1.0)
+    IsPow2,
+    /// Number of bits set in the integer part of the operand, truncated and made positive as in IsPow2
+    Popcount,
+    /// Converts degrees to radians
+    Radians,
+    /// Converts radians to degrees
+    Degrees,
+    /// Pseudo-random value in [0, 1) computed from the bits of the operand, always the same for
+    /// the same operand
+    Noise,
+    /// Clamps the operand into [0, 1]
+    Clamp01,
 }
 
 impl UnaryOperator {
+    fn apply_interval(self, operand: Interval) -> Interval {
+        match self {
+            UnaryOperator::Minus => (-operand.1, -operand.0),
+            // Monotonic functions
+            UnaryOperator::Exp | UnaryOperator::Exp2 | UnaryOperator::Radians | UnaryOperator::Degrees |
+            UnaryOperator::Clamp01 => {
+                (self.apply(operand.0), self.apply(operand.1))
+            }
+            UnaryOperator::Sin | UnaryOperator::Cos | UnaryOperator::Fract => (-1.0, 1.0),
+            UnaryOperator::IsPow2 => (0.0, 1.0),
+            UnaryOperator::Popcount => (0.0, 64.0),
+            UnaryOperator::Noise => (0.0, 1.0),
+        }
+    }
+
+
     fn apply(self, operand: f64) -> f64 {
         match self {
             UnaryOperator::Sin => operand.sin(),
             UnaryOperator::Cos => operand.cos(),
+            UnaryOperator::Exp => operand.exp(),
+            UnaryOperator::Exp2 => operand.exp2(),
             UnaryOperator::Minus => (-operand),
+            UnaryOperator::Fract => operand.fract(),
+            UnaryOperator::IsPow2 => if integer_part(operand).count_ones() == 1 {1.0} else {0.0},
+            UnaryOperator::Popcount => integer_part(operand).count_ones() as f64,
+            UnaryOperator::Radians => operand.to_radians(),
+            UnaryOperator::Degrees => operand.to_degrees(),
+            UnaryOperator::Noise => noise(operand),
+            UnaryOperator::Clamp01 => operand.max(0.0).min(1.0),
         }
     }
 }
 
+/// How evaluate_int converts the result to an integer
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum RoundingPolicy {
+    Floor,
+    Ceil,
+    /// Halfway cases are rounded away from zero
+    Round,
+    Trunc,
+}
+
+/// Base used to format evaluation results
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Radix {
+    Binary,
+    Octal,
+    Decimal,
+    Hexadecimal,
+}
+
+/// Formats a whole number in the given base, with a "0b", "0o" or "0x" prefix
+///
+/// Values which are not whole numbers (or do not fit in an i64) are formatted in decimal.
+pub fn format_result(value: f64, radix: Radix) -> String {
+    if !value.is_finite() || value.fract() != 0.0 || value.abs() >= ::std::i64::MAX as f64 {
+        return value.to_string();
+    }
+    let integer = value as i64;
+    let sign = if integer < 0 {"-"} else {""};
+    match radix {
+        Radix::Binary => format!("{}0b{:b}", sign, integer.abs()),
+        Radix::Octal => format!("{}0o{:o}", sign, integer.abs()),
+        Radix::Decimal => integer.to_string(),
+        Radix::Hexadecimal => format!("{}0x{:x}", sign, integer.abs()),
+    }
+}
+
 #[derive(Clone,Debug)]
 pub struct ExpressionEvaluator {
     expression: Vec<ExpressionMember>,
+    // Each intermediate result is clamped into these bounds
+    bounds: Option<(f64,f64)>,
+    // Each intermediate result is reduced modulo this value
+    modulus: Option<f64>,
+    // Operands of integer operators must fit in an i64
+    strict_integers: bool,
+}
+
+/// Scope a variable was read from during an evaluation, and its value
+#[derive(Clone,Debug,PartialEq)]
+pub struct VariableResolution {
+    pub name: String,
+    pub local: bool,
+    pub value: f64,
 }
 
 #[derive(Debug,Clone)]
 pub enum ExpressionError {
     VariableNotFound(String),
-    InvalidExpression(String),
+    /// Messages are static strings when possible, so that errors such as a missing result do
+    /// not allocate
+    InvalidExpression(Cow<'static, str>),
+    /// The result cannot be converted to an integer (see evaluate_int)
+    NonFinite(f64),
+    /// An intermediate result is not a whole number in modular arithmetic (see set_modulus)
+    NotAnInteger(f64),
+    /// Fewer operands on the stack than the operator needs
+    WrongArity { operator: Operator, expected: usize, found: usize },
+    /// The iterations of fixpoint did not converge, the last value is given
+    NotConverged(f64),
+    /// An operand of an integer operator does not fit in an i64 (see set_strict_integers)
+    IntegerOverflow(f64),
+}
+
+impl Display for ExpressionError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            VariableNotFound(ref name) => write!(fmt, "Variable {} not found", name),
+            InvalidExpression(ref message) => write!(fmt, "Invalid expression: {}", message),
+            NonFinite(value) => write!(fmt, "Result {} cannot be converted to an integer", value),
+            NotAnInteger(value) => write!(fmt, "Intermediate result {} is not an integer", value),
+            WrongArity { operator, expected, found } => {
+                write!(fmt, "Operator {:?} expects {} operand(s), found {}", operator, expected, found)
+            }
+            NotConverged(value) => write!(fmt, "Iterations did not converge, last value {}", value),
+            IntegerOverflow(value) => write!(fmt, "Operand {} does not fit in a 64 bits integer", value),
+        }
+    }
+}
+
+impl Error for ExpressionError {
+    fn description(&self) -> &str {
+        match *self {
+            VariableNotFound(_) => "variable not found",
+            InvalidExpression(_) => "invalid expression",
+            NonFinite(_) => "result cannot be converted to an integer",
+            NotAnInteger(_) => "intermediate result is not an integer",
+            WrongArity { .. } => "wrong number of operands",
+            NotConverged(_) => "iterations did not converge",
+            IntegerOverflow(_) => "operand does not fit in a 64 bits integer",
+        }
+    }
 }
 
 impl ExpressionEvaluator {
@@ -161,6 +748,41 @@ impl ExpressionEvaluator {
     pub fn evaluate<T,V>(&self, global_variables: &T, local_variables: &V) -> Result<f64,ExpressionError>
     where T: Store,
           V: Store {
+        self.evaluate_reporting(global_variables, local_variables, &mut |_, _| Ok(()), &mut |_, _| {})
+    }
+
+    /// Evaluates an expression, also recording where each variable was read and its value
+    pub fn evaluate_with_resolution<T,V>(&self, global_variables: &T, local_variables: &V) -> Result<(f64,Vec<VariableResolution>),ExpressionError>
+    where T: Store,
+          V: Store {
+        let mut resolutions = Vec::new();
+        let result = try!(self.evaluate_reporting(global_variables, local_variables, &mut |_, _| Ok(()), &mut |variable, value| {
+            resolutions.push(VariableResolution {
+                name: variable.name.clone(),
+                local: variable.local,
+                value: value,
+            });
+        }));
+        Ok((result, resolutions))
+    }
+
+    /// Evaluates an expression, calling hook with the name of each variable (and whether it is
+    /// local) just before it is read
+    ///
+    /// An error returned by the hook aborts the evaluation and is returned as is.
+    pub fn evaluate_with_read_hook<T,V,H>(&self, global_variables: &T, local_variables: &V, mut hook: H) -> Result<f64,ExpressionError>
+    where T: Store,
+          V: Store,
+          H: FnMut(&str, bool) -> Result<(),ExpressionError> {
+        self.evaluate_reporting(global_variables, local_variables, &mut hook, &mut |_, _| {})
+    }
+
+    // Evaluates an expression, calling before_read before and on_read after every variable read
+    fn evaluate_reporting<T,V,B,F>(&self, global_variables: &T, local_variables: &V, before_read: &mut B, on_read: &mut F) -> Result<f64,ExpressionError>
+    where T: Store,
+          V: Store,
+          B: FnMut(&str, bool) -> Result<(),ExpressionError>,
+          F: FnMut(&Variable, f64) {
         // The algorithm to execute such an expression is fairly simple:
         //  - Create a stack to hold temporary values
         //  - Iterate through the expression members
@@ -171,23 +793,124 @@ impl ExpressionEvaluator {
         //  result
         let mut stack = Vec::new();
         for member in self.expression.iter() {
-            match *member {
-                ExpressionMember::Constant(value) => stack.push(value),
-                ExpressionMember::Variable(Variable{local,ref name}) => {
-                    let value = if local {
-                        // Error to reference an undefined variable
-                        try!(local_variables.get_attribute(&name).ok_or_else(|| VariableNotFound(name.clone())))
-                    } else {
-                        try!(global_variables.get_attribute(&name).ok_or_else(|| VariableNotFound(name.clone())))
-                    };
-                    stack.push(value);
-                },
-                ExpressionMember::Op(operator) => {
-                    let result = try!(operator.apply(&mut stack));
-                    stack.push(result);
-                    // First member will be the second one in the stack
+            try!(self.evaluate_member(member, &mut stack, global_variables, local_variables, before_read, on_read));
+        }
+        let result = try!(stack.pop().ok_or_else(|| InvalidExpression("No result at the end of the expression".into())));
+        if !stack.is_empty() {
+            return Err(InvalidExpression("Stack not empty at the end of the expression".into()));
+        }
+        Ok(result)
+    }
+
+    /// Evaluates an expression, recording the state of the stack after each member
+    pub fn evaluate_steps<T,V>(&self, global_variables: &T, local_variables: &V) -> Result<Vec<(ExpressionMember,Vec<f64>)>,ExpressionError>
+    where T: Store,
+          V: Store {
+        let mut stack = Vec::new();
+        let mut steps = Vec::with_capacity(self.expression.len());
+        for member in self.expression.iter() {
+            try!(self.evaluate_member(member, &mut stack, global_variables, local_variables, &mut |_, _| Ok(()), &mut |_, _| {}));
+            steps.push((member.clone(), stack.clone()));
+        }
+        if stack.len() != 1 {
+            return Err(InvalidExpression("Stack must contain exactly one value at the end of the expression".into()));
+        }
+        Ok(steps)
+    }
+
+    /// Evaluates an expression and converts the result to an integer
+    ///
+    /// Fails with NonFinite if the result is NaN, infinite or does not fit in an i64.
+    pub fn evaluate_int<T,V>(&self, global_variables: &T, local_variables: &V, policy: RoundingPolicy) -> Result<i64,ExpressionError>
+    where T: Store,
+          V: Store {
+        let result = try!(self.evaluate(global_variables, local_variables));
+        let rounded = match policy {
+            RoundingPolicy::Floor => result.floor(),
+            RoundingPolicy::Ceil => result.ceil(),
+            RoundingPolicy::Round => result.round(),
+            RoundingPolicy::Trunc => result.trunc(),
+        };
+        if !fits_in_i64(rounded) {
+            return Err(NonFinite(result));
+        }
+        Ok(rounded as i64)
+    }
+
+    /// Computes an interval containing every possible result, given intervals containing the
+    /// possible values of the variables
+    ///
+    /// The result is conservative: it may be wider than the actual range, for instance for
+    /// non-monotonic functions such as sin. Past values of a variable are assumed to be in the
+    /// same interval as its current value.
+    pub fn evaluate_interval(&self, global_ranges: &HashMap<String,(f64,f64)>, local_ranges: &HashMap<String,(f64,f64)>) -> Result<(f64,f64),ExpressionError> {
+        self.evaluate_interval_reporting(global_ranges, local_ranges, &mut |_, _, _| {})
+    }
+
+    /// Divisors which may be zero, given the ranges of the variables as in evaluate_interval
+    ///
+    /// Each divisor is formatted as an expression, such as "(x - 5)" for "1 / (x - 5)".
+    pub fn risky_divisions(&self, global_ranges: &HashMap<String,(f64,f64)>, local_ranges: &HashMap<String,(f64,f64)>) -> Result<Vec<String>,ExpressionError> {
+        // Index of the first member of each subexpression on the evaluation stack
+        let mut starts: Vec<usize> = Vec::new();
+        let mut risky = Vec::new();
+        try!(self.evaluate_interval_reporting(global_ranges, local_ranges, &mut |index, member, stack| {
+            if let ExpressionMember::Op(Operator::Binary(BinaryOperator::Divide)) = *member {
+                if let (Some(&(min, max)), Some(&start)) = (stack.last(), starts.last()) {
+                    if min <= 0.0 && max >= 0.0 {
+                        let divisor = ExpressionEvaluator::new(self.expression[start..index].to_vec());
+                        risky.push(divisor.to_string());
+                    }
                 }
             }
+            let start = starts.len().saturating_sub(member.nb_operands());
+            let first = starts.split_off(start).into_iter().next().unwrap_or(index);
+            starts.push(first);
+        }));
+        Ok(risky)
+    }
+
+    // Same as evaluate_interval, calling before_member with the index of each member and the
+    // stack of intervals before it is applied
+    fn evaluate_interval_reporting<F>(&self, global_ranges: &HashMap<String,(f64,f64)>, local_ranges: &HashMap<String,(f64,f64)>, before_member: &mut F) -> Result<(f64,f64),ExpressionError>
+    where F: FnMut(usize, &ExpressionMember, &[Interval]) {
+        let mut stack = Vec::new();
+        for (index, member) in self.expression.iter().enumerate() {
+            before_member(index, member, &stack);
+            let interval = match *member {
+                ExpressionMember::Constant(value) => (value, value),
+                ExpressionMember::Variable(Variable{local,ref name}) |
+                ExpressionMember::Previous(Variable{local,ref name}) => {
+                    if let ExpressionMember::Previous(_) = *member {
+                        try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for prev({})", name).into())));
+                    }
+                    let ranges = if local {local_ranges} else {global_ranges};
+                    try!(ranges.get(name).cloned().ok_or_else(|| VariableNotFound(name.clone())))
+                }
+                ExpressionMember::VariableOr(Variable{local,ref name}) => {
+                    let default: Interval = try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for {} ??", name).into())));
+                    let ranges = if local {local_ranges} else {global_ranges};
+                    match ranges.get(name) {
+                        Some(range) => hull(&[range.0, range.1, default.0, default.1]),
+                        None => default,
+                    }
+                }
+                ExpressionMember::SumGlobals(ref prefix) => {
+                    global_ranges.iter().filter(|&(name, _)| name.starts_with(prefix.as_str()))
+                                        .fold((0.0, 0.0), |acc, (_, range)| (acc.0 + range.0, acc.1 + range.1))
+                }
+                ExpressionMember::Op(operator) => {
+                    let mut interval = try!(operator.apply_interval(&mut stack));
+                    if let Some(modulus) = self.modulus {
+                        interval = (0.0, modulus);
+                    }
+                    if let Some((min, max)) = self.bounds {
+                        interval = (interval.0.max(min).min(max), interval.1.max(min).min(max));
+                    }
+                    interval
+                }
+            };
+            stack.push(interval);
         }
         let result = try!(stack.pop().ok_or_else(|| InvalidExpression("No result at the end of the expression".into())));
         if !stack.is_empty() {
@@ -196,13 +919,62 @@ impl ExpressionEvaluator {
         Ok(result)
     }
 
+    /// Evaluates an expression using a single store for both local and global variables
+    pub fn evaluate_flat<T: Store>(&self, variables: &T) -> Result<f64,ExpressionError> {
+        self.evaluate(variables, variables)
+    }
+
+    /// Solves x = f(x), the expression being f and the local variable named variable being x
+    ///
+    /// Starting from start, the expression is evaluated again with its previous result until two
+    /// successive values differ by less than tolerance, and fails with NotConverged after
+    /// max_iterations evaluations.
+    pub fn fixpoint<T: Store>(&self, variable: &str, start: f64, tolerance: f64, max_iterations: usize, global_variables: &T) -> Result<f64,ExpressionError> {
+        let mut local_variables = HashMap::new();
+        let mut value = start;
+        for _ in 0..max_iterations {
+            local_variables.insert(variable.to_string(), value);
+            let next = try!(self.evaluate(global_variables, &local_variables));
+            if (next - value).abs() < tolerance {
+                return Ok(next);
+            }
+            value = next;
+        }
+        Err(NotConverged(value))
+    }
+
+    /// Height of the equivalent expression tree, 1 for a single constant or variable
+    pub fn depth(&self) -> usize {
+        // Depth of each subtree on the evaluation stack
+        let mut stack: Vec<usize> = Vec::new();
+        for member in self.expression.iter() {
+            let start = stack.len().saturating_sub(member.nb_operands());
+            let children = stack.split_off(start).into_iter().max().unwrap_or(0);
+            stack.push(children + 1);
+        }
+        stack.into_iter().max().unwrap_or(0)
+    }
+
+    /// Largest number of values on the stack while evaluating the expression
+    pub fn required_stack_depth(&self) -> usize {
+        let mut size: usize = 0;
+        let mut peak = 0;
+        for member in self.expression.iter() {
+            // Operands are popped before the result is pushed
+            size = size.saturating_sub(member.nb_operands()) + 1;
+            peak = ::std::cmp::max(peak, size);
+        }
+        peak
+    }
+
     /// Get list of global variables referenced by this expression
     pub fn get_global_variable_list(&self) -> Vec<String> {
         self.expression.iter().filter_map(|member| {
-            if let ExpressionMember::Variable(Variable{local: false, ref name}) = *member {
-                Some(name.clone())
-            } else {
-                None
+            match *member {
+                ExpressionMember::Variable(Variable{local: false, ref name}) |
+                ExpressionMember::Previous(Variable{local: false, ref name}) |
+                ExpressionMember::VariableOr(Variable{local: false, ref name}) => Some(name.clone()),
+                _ => None,
             }
         }).collect()
     }
@@ -210,18 +982,273 @@ impl ExpressionEvaluator {
     /// Get list of local variables referenced by this expression
     pub fn get_local_variable_list(&self) -> Vec<String> {
         self.expression.iter().filter_map(|member| {
-            if let ExpressionMember::Variable(Variable{local: true, ref name}) = *member {
-                Some(name.clone())
-            } else {
-                None
+            match *member {
+                ExpressionMember::Variable(Variable{local: true, ref name}) |
+                ExpressionMember::Previous(Variable{local: true, ref name}) |
+                ExpressionMember::VariableOr(Variable{local: true, ref name}) => Some(name.clone()),
+                _ => None,
             }
         }).collect()
     }
 
+    /// Renames every reference to the variable "from" in the given namespace
+    pub fn rename_variable(&mut self, from: &str, to: &str, local: bool) {
+        for member in self.expression.iter_mut() {
+            match *member {
+                ExpressionMember::Variable(ref mut variable) |
+                ExpressionMember::Previous(ref mut variable) |
+                ExpressionMember::VariableOr(ref mut variable) => {
+                    if variable.local == local && variable.name == from {
+                        variable.name = to.to_string();
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Same expression, with the operands of commutative operators (+, *, min and max) sorted,
+    /// so that equivalent expressions such as "a + b" and "b + a" have the same members
+    pub fn canonicalize(&self) -> ExpressionEvaluator {
+        // Members of each subexpression on the evaluation stack
+        let mut stack: Vec<Vec<ExpressionMember>> = Vec::new();
+        for member in self.expression.iter() {
+            let start = stack.len().saturating_sub(member.nb_operands());
+            let mut operands = stack.split_off(start);
+            let commutative = match *member {
+                ExpressionMember::Op(Operator::Binary(BinaryOperator::Plus)) |
+                ExpressionMember::Op(Operator::Binary(BinaryOperator::Multiply)) |
+                ExpressionMember::Op(Operator::Binary(BinaryOperator::Min)) |
+                ExpressionMember::Op(Operator::Binary(BinaryOperator::Max)) |
+                ExpressionMember::Op(Operator::Variadic(VariadicOperator::Min, _)) |
+                ExpressionMember::Op(Operator::Variadic(VariadicOperator::Max, _)) => true,
+                _ => false,
+            };
+            if commutative {
+                operands.sort_by_key(|operand| format!("{:?}", operand));
+            }
+            let mut subexpression: Vec<ExpressionMember> = operands.into_iter().flat_map(|operand| operand).collect();
+            subexpression.push(member.clone());
+            stack.push(subexpression);
+        }
+        ExpressionEvaluator {
+            expression: stack.into_iter().flat_map(|subexpression| subexpression).collect(),
+            bounds: self.bounds,
+            modulus: self.modulus,
+            strict_integers: self.strict_integers,
+        }
+    }
+
+    /// Members of the expression, in postfix order
+    pub fn members(&self) -> &[ExpressionMember] {
+        &self.expression
+    }
+
+    /// Values of the constants of the expression, in postfix order
+    pub fn constants(&self) -> Vec<f64> {
+        self.expression.iter().filter_map(|member| match *member {
+            ExpressionMember::Constant(value) => Some(value),
+            _ => None,
+        }).collect()
+    }
+
+    /// Indices in members of the constants of the expression
+    pub fn constant_positions(&self) -> Vec<usize> {
+        self.expression.iter().enumerate().filter_map(|(index, member)| match *member {
+            ExpressionMember::Constant(_) => Some(index),
+            _ => None,
+        }).collect()
+    }
+
+    /// Replaces the value of the constant at the given index in members
+    ///
+    /// Fails if there is no constant at this index.
+    pub fn set_constant(&mut self, index: usize, value: f64) -> Result<(),ExpressionError> {
+        match self.expression.get_mut(index) {
+            Some(&mut ExpressionMember::Constant(ref mut constant)) => {
+                *constant = value;
+                Ok(())
+            }
+            _ => Err(InvalidExpression(format!("No constant at index {}", index).into())),
+        }
+    }
+
+    /// Saturates every intermediate result into [min, max]
+    ///
+    /// This prevents intermediate results from overflowing to infinity
+    pub fn set_bounds(&mut self, min: f64, max: f64) {
+        self.bounds = Some((min, max));
+    }
+
+    /// Switches to wrap-around integer arithmetic: every intermediate result is reduced into
+    /// [0, modulus), and evaluation fails with NotAnInteger if one is not a whole number
+    ///
+    /// The modulus is expected to be a positive whole number.
+    pub fn set_modulus(&mut self, modulus: f64) {
+        self.modulus = Some(modulus);
+    }
+
+    /// Makes evaluation fail with IntegerOverflow when an operand of an operator working on
+    /// integer parts (gcd, lcm, every, is_pow2, popcount, bitwise and shift operators) is not
+    /// finite or does not fit in an i64, instead of saturating
+    pub fn set_strict_integers(&mut self, strict: bool) {
+        self.strict_integers = strict;
+    }
+
     pub fn new(expression: Vec<ExpressionMember>) -> ExpressionEvaluator {
         ExpressionEvaluator {
-            expression: expression
+            expression: expression,
+            bounds: None,
+            modulus: None,
+            strict_integers: false,
+        }
+    }
+
+    fn evaluate_member<T,V,B,F>(&self, member: &ExpressionMember, stack: &mut Vec<f64>, global_variables: &T, local_variables: &V, before_read: &mut B, on_read: &mut F) -> Result<(),ExpressionError>
+    where T: Store,
+          V: Store,
+          B: FnMut(&str, bool) -> Result<(),ExpressionError>,
+          F: FnMut(&Variable, f64) {
+        match *member {
+            ExpressionMember::Constant(value) => stack.push(value),
+            ExpressionMember::Variable(ref variable) => {
+                let Variable{local,ref name} = *variable;
+                try!(before_read(name, local));
+                let value = if local {
+                    // Error to reference an undefined variable
+                    try!(local_variables.get_attribute(&name).ok_or_else(|| VariableNotFound(name.clone())))
+                } else {
+                    try!(global_variables.get_attribute(&name).ok_or_else(|| VariableNotFound(name.clone())))
+                };
+                on_read(variable, value);
+                stack.push(value);
+            },
+            ExpressionMember::SumGlobals(ref prefix) => {
+                let mut names = global_variables.names();
+                // Sorted so that the floating point rounding does not depend on the store
+                names.sort();
+                let mut sum = 0.0;
+                for name in names.iter().filter(|name| name.starts_with(prefix.as_str())) {
+                    try!(before_read(name, false));
+                    if let Some(value) = global_variables.get_attribute(name) {
+                        on_read(&Variable::new(false, name.clone()), value);
+                        sum += value;
+                    }
+                }
+                stack.push(sum);
+            },
+            ExpressionMember::Previous(ref variable) => {
+                let Variable{local,ref name} = *variable;
+                let steps_back = try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for prev({})", name).into())));
+                if !(steps_back >= 0.0) || steps_back.fract() != 0.0 {
+                    return Err(InvalidExpression(format!("Invalid number of steps {} for prev({})", steps_back, name).into()));
+                }
+                try!(before_read(name, local));
+                let value = if local {
+                    try!(local_variables.get_history(&name, steps_back as usize).ok_or_else(|| VariableNotFound(name.clone())))
+                } else {
+                    try!(global_variables.get_history(&name, steps_back as usize).ok_or_else(|| VariableNotFound(name.clone())))
+                };
+                on_read(variable, value);
+                stack.push(value);
+            }
+            ExpressionMember::VariableOr(ref variable) => {
+                let Variable{local,ref name} = *variable;
+                let default = try!(stack.pop().ok_or_else(|| InvalidExpression(format!("Missing member for {} ??", name).into())));
+                try!(before_read(name, local));
+                let value = if local {
+                    local_variables.get_attribute(&name)
+                } else {
+                    global_variables.get_attribute(&name)
+                };
+                match value {
+                    Some(value) => {
+                        on_read(variable, value);
+                        stack.push(value);
+                    }
+                    None => stack.push(default),
+                }
+            }
+            ExpressionMember::Op(operator) => {
+                if self.strict_integers && operator.uses_integer_parts() {
+                    let start = stack.len().saturating_sub(member.nb_operands());
+                    if let Some(&value) = stack[start..].iter().find(|value| !fits_in_i64(**value)) {
+                        return Err(IntegerOverflow(value));
+                    }
+                }
+                let mut result = try!(operator.apply(stack));
+                if let Some(modulus) = self.modulus {
+                    if result.fract() != 0.0 || !result.is_finite() {
+                        return Err(NotAnInteger(result));
+                    }
+                    result = ((result % modulus) + modulus) % modulus;
+                }
+                if let Some((min, max)) = self.bounds {
+                    result = result.max(min).min(max);
+                }
+                stack.push(result);
+                // First member will be the second one in the stack
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Expression caching its last result, evaluated again only when a variable it reads changes
+#[derive(Clone,Debug)]
+pub struct ReactiveExpression {
+    expression: ExpressionEvaluator,
+    cache: Option<f64>,
+    recomputations: usize,
+}
+
+impl ReactiveExpression {
+    pub fn new(expression: ExpressionEvaluator) -> ReactiveExpression {
+        ReactiveExpression {
+            expression: expression,
+            cache: None,
+            recomputations: 0,
+        }
+    }
+
+    /// Returns the cached result, unless there is none yet or the expression reads one of the
+    /// changed variables
+    pub fn evaluate<T,V>(&mut self, global_variables: &T, local_variables: &V, changed: &[Variable]) -> Result<f64,ExpressionError>
+    where T: Store,
+          V: Store {
+        if let Some(value) = self.cache {
+            if !changed.iter().any(|variable| self.depends_on(variable)) {
+                return Ok(value);
+            }
+        }
+        self.cache = None;
+        self.recomputations += 1;
+        let value = try!(self.expression.evaluate(global_variables, local_variables));
+        self.cache = Some(value);
+        Ok(value)
+    }
+
+    /// Number of times the expression was actually evaluated
+    pub fn recomputations(&self) -> usize {
+        self.recomputations
+    }
+
+    fn depends_on(&self, variable: &Variable) -> bool {
+        let variables = if variable.local {
+            self.expression.get_local_variable_list()
+        } else {
+            self.expression.get_global_variable_list()
+        };
+        if variables.contains(&variable.name) {
+            return true;
         }
+        // sum_where reads every global variable with its prefix
+        !variable.local && self.expression.expression.iter().any(|member| {
+            match *member {
+                ExpressionMember::SumGlobals(ref prefix) => variable.name.starts_with(prefix.as_str()),
+                _ => false,
+            }
+        })
     }
 }
 
@@ -229,10 +1256,15 @@ impl ExpressionEvaluator {
 mod test {
     use std::collections::HashMap;
 
+    use super::Store;
     use super::ExpressionMember::*;
     use super::Operator;
     use super::BinaryOperator;
+    use super::VariadicOperator;
+    use super::UnaryOperator;
     use super::ExpressionEvaluator;
+    use super::ExpressionError;
+    use super::{format_result,Radix,RoundingPolicy,Layered,ReactiveExpression};
     #[test]
     fn evaluate_int() {
         let context = HashMap::new();
@@ -275,4 +1307,349 @@ mod test {
             ]);
         assert!(expression.evaluate(&context,&()).unwrap() == 39.0);
     }
+
+    #[test]
+    fn evaluate_flat() {
+        use super::Variable as Var;
+        let mut context = HashMap::new();
+        context.insert("x".to_string(), 1.0);
+        context.insert("y".to_string(), 2.0);
+        // Calculates x + $y
+        let expression = ExpressionEvaluator::new(vec! [
+            Variable(Var::new(true, "x".to_string())),
+            Variable(Var::new(false, "y".to_string())),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        assert!(expression.evaluate_flat(&context).unwrap() == 3.0);
+    }
+
+    #[test]
+    fn variadic_operator() {
+        let context = HashMap::new();
+        let expression = ExpressionEvaluator::new(vec! [
+            Constant(10.0),
+            Constant(3.0),
+            Constant(1.0),
+            Constant(2.0),
+            Op(Operator::Variadic(VariadicOperator::Min, 3)),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        assert!(expression.evaluate(&context,&()).unwrap() == 11.0);
+
+        let expression = ExpressionEvaluator::new(vec! [
+            Constant(1.0),
+            Op(Operator::Variadic(VariadicOperator::Max, 2)),
+            ]);
+        assert!(expression.evaluate(&context,&()).is_err());
+    }
+
+    #[test]
+    fn evaluate_steps() {
+        let context = HashMap::new();
+        let expression = ExpressionEvaluator::new(vec! [
+            Constant(1.0),
+            Constant(2.0),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        let stacks: Vec<Vec<f64>> = expression.evaluate_steps(&context,&()).unwrap()
+            .into_iter().map(|(_, stack)| stack).collect();
+        assert_eq!(stacks, vec![vec![1.0], vec![1.0, 2.0], vec![3.0]]);
+    }
+
+    #[test]
+    fn saturating_bounds() {
+        let context = HashMap::new();
+        let mut expression = ExpressionEvaluator::new(vec! [
+            Constant(1e308),
+            Constant(10.0),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            ]);
+        assert!(expression.evaluate(&context,&()).unwrap().is_infinite());
+        expression.set_bounds(-1000.0, 1000.0);
+        assert!(expression.evaluate(&context,&()).unwrap() == 1000.0);
+
+        let mut expression = ExpressionEvaluator::new(vec! [
+            Constant(1e308),
+            Constant(10.0),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            Constant(10.0),
+            Op(Operator::Binary(BinaryOperator::Minus)),
+            Constant(100.0),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        expression.set_bounds(-1000.0, 1000.0);
+        assert!(expression.evaluate(&context,&()).unwrap() == 1000.0);
+    }
+
+    #[test]
+    fn modular_arithmetic() {
+        // Calculates (7 * 8 + 5) * 3
+        let mut expression = ExpressionEvaluator::new(vec! [
+            Constant(7.0),
+            Constant(8.0),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            Constant(5.0),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            Constant(3.0),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            ]);
+        expression.set_modulus(10.0);
+        // 56 is reduced to 6, then 11 to 1, then 3
+        assert!(expression.evaluate(&(),&()).unwrap() == 3.0);
+
+        let mut expression = ExpressionEvaluator::new(vec! [
+            Constant(7.0),
+            Constant(2.0),
+            Op(Operator::Binary(BinaryOperator::Divide)),
+            ]);
+        expression.set_modulus(10.0);
+        match expression.evaluate(&(),&()) {
+            Err(ExpressionError::NotAnInteger(value)) => assert_eq!(value, 3.5),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    // Store whose variable "hp" went through 10, 20, 30 (current value)
+    struct HistoryStore;
+
+    impl Store for HistoryStore {
+        fn get_attribute(&self, var: &str) -> Option<f64> {
+            self.get_history(var, 0)
+        }
+
+        fn set_attribute(&mut self, _: &str, _: f64) -> Result<Option<f64>,()> {
+            Err(())
+        }
+
+        fn get_history(&self, var: &str, steps_back: usize) -> Option<f64> {
+            if var != "hp" {
+                return None;
+            }
+            [30.0, 20.0, 10.0].get(steps_back).cloned()
+        }
+    }
+
+    #[test]
+    fn previous_value() {
+        use super::Variable as Var;
+        // Calculates $hp - prev($hp, 2)
+        let expression = ExpressionEvaluator::new(vec! [
+            Variable(Var::new(false, "hp".to_string())),
+            Constant(2.0),
+            Previous(Var::new(false, "hp".to_string())),
+            Op(Operator::Binary(BinaryOperator::Minus)),
+            ]);
+        assert!(expression.evaluate(&HistoryStore,&()).unwrap() == 20.0);
+        assert_eq!(expression.get_global_variable_list(), vec!["hp".to_string(), "hp".to_string()]);
+
+        let expression = ExpressionEvaluator::new(vec! [
+            Constant(3.0),
+            Previous(Var::new(false, "hp".to_string())),
+            ]);
+        assert!(expression.evaluate(&HistoryStore,&()).is_err());
+    }
+
+    #[test]
+    fn evaluate_with_resolution() {
+        use super::Variable as Var;
+        use super::VariableResolution;
+        let mut global = HashMap::new();
+        global.insert("x".to_string(), 1.0);
+        let mut local = HashMap::new();
+        local.insert("x".to_string(), 10.0);
+        // Calculates x + $x
+        let expression = ExpressionEvaluator::new(vec! [
+            Variable(Var::new(true, "x".to_string())),
+            Variable(Var::new(false, "x".to_string())),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        let (result, resolutions) = expression.evaluate_with_resolution(&global,&local).unwrap();
+        assert!(result == 11.0);
+        assert_eq!(resolutions, vec![
+            VariableResolution { name: "x".to_string(), local: true, value: 10.0 },
+            VariableResolution { name: "x".to_string(), local: false, value: 1.0 },
+            ]);
+    }
+
+    #[test]
+    fn sum_globals_resolution() {
+        use super::VariableResolution;
+        let mut global = HashMap::new();
+        global.insert("gold_b".to_string(), 2.0);
+        global.insert("gold_a".to_string(), 1.0);
+        global.insert("silver".to_string(), 5.0);
+        let expression = ExpressionEvaluator::new(vec! [SumGlobals("gold_".to_string())]);
+        let (result, resolutions) = expression.evaluate_with_resolution(&global,&()).unwrap();
+        assert!(result == 3.0);
+        assert_eq!(resolutions, vec![
+            VariableResolution { name: "gold_a".to_string(), local: false, value: 1.0 },
+            VariableResolution { name: "gold_b".to_string(), local: false, value: 2.0 },
+            ]);
+    }
+
+    #[test]
+    fn is_pure() {
+        assert!(Operator::Unary(UnaryOperator::Noise).is_pure());
+        assert!(Operator::Binary(BinaryOperator::Plus).is_pure());
+        assert!(!Operator::Binary(BinaryOperator::Rand).is_pure());
+    }
+
+    #[test]
+    fn required_stack_depth() {
+        // 1 2 + 3 *
+        let expression = ExpressionEvaluator::new(vec! [
+            Constant(1.0),
+            Constant(2.0),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            Constant(3.0),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            ]);
+        assert_eq!(expression.required_stack_depth(), 2);
+        // 1 2 3 4 + * +
+        let expression = ExpressionEvaluator::new(vec! [
+            Constant(1.0),
+            Constant(2.0),
+            Constant(3.0),
+            Constant(4.0),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        assert_eq!(expression.required_stack_depth(), 4);
+        assert_eq!(ExpressionEvaluator::new(vec![]).required_stack_depth(), 0);
+    }
+
+    #[test]
+    fn sorted_snapshot() {
+        let mut store = HashMap::new();
+        store.insert("mana".to_string(), 3.0);
+        store.insert("armor".to_string(), 1.0);
+        store.insert("hp".to_string(), 2.0);
+        assert_eq!(super::sorted_snapshot(&store), vec![
+            ("armor".to_string(), 1.0),
+            ("hp".to_string(), 2.0),
+            ("mana".to_string(), 3.0),
+            ]);
+        assert!(super::sorted_snapshot(&()).is_empty());
+    }
+
+    #[test]
+    fn evaluate_with_read_hook() {
+        use super::Variable as Var;
+        let mut global = HashMap::new();
+        global.insert("secret".to_string(), 42.0);
+        let mut local = HashMap::new();
+        local.insert("x".to_string(), 1.0);
+        // Calculates x + $secret
+        let expression = ExpressionEvaluator::new(vec! [
+            Variable(Var::new(true, "x".to_string())),
+            Variable(Var::new(false, "secret".to_string())),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+            ]);
+        let mut reads = Vec::new();
+        let result = expression.evaluate_with_read_hook(&global, &local, |name, local| {
+            reads.push((name.to_string(), local));
+            if name == "secret" {
+                Err(ExpressionError::InvalidExpression("reading secret is forbidden".into()))
+            } else {
+                Ok(())
+            }
+        });
+        match result {
+            Err(ExpressionError::InvalidExpression(ref message)) => assert_eq!(message, "reading secret is forbidden"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(reads, vec![("x".to_string(), true), ("secret".to_string(), false)]);
+        assert!(expression.evaluate_with_read_hook(&global, &local, |_, _| Ok(())).unwrap() == 43.0);
+    }
+
+    #[test]
+    fn static_error_messages() {
+        use std::borrow::Cow;
+        // Stack underflows do not allocate an error message
+        match ExpressionEvaluator::new(vec![]).evaluate(&(), &()) {
+            Err(ExpressionError::InvalidExpression(Cow::Borrowed(_))) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let expression = ExpressionEvaluator::new(vec![Op(Operator::Binary(BinaryOperator::Plus))]);
+        match expression.evaluate(&(), &()) {
+            Err(ExpressionError::WrongArity { .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let expression = ExpressionEvaluator::new(vec![
+            Constant(1.0),
+            Op(Operator::Binary(BinaryOperator::Plus)),
+        ]);
+        match expression.evaluate(&(), &()) {
+            Err(ExpressionError::WrongArity { expected: 2, found: 1, .. }) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn format_in_radix() {
+        assert_eq!(format_result(255.0, Radix::Hexadecimal), "0xff");
+        assert_eq!(format_result(-8.0, Radix::Octal), "-0o10");
+        assert_eq!(format_result(5.0, Radix::Binary), "0b101");
+        assert_eq!(format_result(2.5, Radix::Hexadecimal), "2.5");
+    }
+
+    #[test]
+    fn evaluate_int_rounding() {
+        let expression = ExpressionEvaluator::new(vec![Constant(2.5)]);
+        assert_eq!(expression.evaluate_int(&(), &(), RoundingPolicy::Floor).unwrap(), 2);
+        assert_eq!(expression.evaluate_int(&(), &(), RoundingPolicy::Ceil).unwrap(), 3);
+        assert_eq!(expression.evaluate_int(&(), &(), RoundingPolicy::Round).unwrap(), 3);
+        assert_eq!(expression.evaluate_int(&(), &(), RoundingPolicy::Trunc).unwrap(), 2);
+        let expression = ExpressionEvaluator::new(vec![Constant(1e30)]);
+        match expression.evaluate_int(&(), &(), RoundingPolicy::Trunc) {
+            Err(ExpressionError::NonFinite(value)) => assert_eq!(value, 1e30),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn layered_store() {
+        let mut overrides = HashMap::new();
+        overrides.insert("speed".to_string(), 2.0);
+        let mut defaults = HashMap::new();
+        defaults.insert("speed".to_string(), 1.0);
+        defaults.insert("health".to_string(), 100.0);
+        {
+            let mut layered = Layered::new(&mut overrides, vec![&defaults]);
+            assert_eq!(layered.get_attribute("speed"), Some(2.0));
+            assert_eq!(layered.get_attribute("health"), Some(100.0));
+            assert_eq!(layered.get_attribute("mana"), None);
+            layered.set_attribute("health", 50.0).unwrap();
+        }
+        assert_eq!(overrides.get("health"), Some(&50.0));
+        assert_eq!(defaults.get("health"), Some(&100.0));
+    }
+
+    #[test]
+    fn reactive_expression() {
+        use super::Variable as Var;
+        let mut global = HashMap::new();
+        global.insert("hp".to_string(), 10.0);
+        global.insert("mana".to_string(), 5.0);
+        // Calculates $hp * 2
+        let mut expression = ReactiveExpression::new(ExpressionEvaluator::new(vec! [
+            Variable(Var::new(false, "hp".to_string())),
+            Constant(2.0),
+            Op(Operator::Binary(BinaryOperator::Multiply)),
+            ]));
+        assert_eq!(expression.evaluate(&global, &(), &[]).unwrap(), 20.0);
+        global.insert("mana".to_string(), 0.0);
+        let changed = [Var::new(false, "mana".to_string()), Var::new(true, "hp".to_string())];
+        assert_eq!(expression.evaluate(&global, &(), &changed).unwrap(), 20.0);
+        assert_eq!(expression.recomputations(), 1);
+        global.insert("hp".to_string(), 3.0);
+        let changed = [Var::new(false, "hp".to_string())];
+        assert_eq!(expression.evaluate(&global, &(), &changed).unwrap(), 6.0);
+        assert_eq!(expression.recomputations(), 2);
+    }
 }
@@ -1,12 +1,18 @@
 //! A rust library to parse and evaluate arithmetic expressions
+//!
+//! Function names (`min`, `step`, `wrap`, ...), `sum_where` and `cycle` are reserved keywords:
+//! they cannot be used as variable names, for instance `$step = $step + 1;` does not parse.
+//! The local variable `ans`, holding the value of the previous instruction, cannot be assigned.
 
 #![cfg_attr(test,feature(box_patterns))]
 
 #[macro_use] extern crate log;
 extern crate rand;
+extern crate lalrpop_util;
 
 pub mod expressions;
 mod parser;
 pub mod rules;
 
-pub use self::parser::parse_rule;
+pub use self::parser::{parse_rule,parse_rules_multi,parse_rule_with_options,parse_rule_with_constants,parse_instruction,tokenize,is_complete,eval,EvalError,ParseError,ParseOptions,Token,Expr};
+pub use self::parser::ast;
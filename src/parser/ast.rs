@@ -1,18 +1,37 @@
 // Mostly taken from Nikomatsakis LALRPOP tutorial
-use std::fmt::{Debug, Formatter, Error};
+use std::fmt::{Debug, Display, Formatter, Error};
 
 pub struct Assignment {
-    pub local: bool,
-    pub variable: String,
+    /// Assigned variables (local, name, byte span), several for chained assignments such as
+    /// "a = b = 0;", none for a bare expression such as "a + b;"
+    pub targets: Vec<(bool, String, (usize, usize))>,
     pub expr: Box<Expr>,
+    /// Only assign a variable which is not set yet ("x ?= 5;")
+    pub if_unset: bool,
 }
 
 impl Assignment {
-    pub fn new(local: bool, variable: String, expr: Box<Expr>) -> Assignment {
+    pub fn new(targets: Vec<(bool, String, (usize, usize))>, expr: Box<Expr>) -> Assignment {
         Assignment {
-            local: local,
-            variable: variable,
+            targets: targets,
             expr: expr,
+            if_unset: false,
+        }
+    }
+
+    /// "cycle(x, n);", which increments x modulo n, as "x = wrap(x + 1, n);"
+    pub fn cycle(target: (bool, String, (usize, usize)), n: Box<Expr>) -> Assignment {
+        let variable = Box::new(Expr::Variable { local: target.0, name: target.1.clone() });
+        let next = Box::new(Expr::Op(variable, Opcode::Plus, Box::new(Expr::Number(1.0))));
+        let span = target.2;
+        Assignment::new(vec![target], Box::new(Expr::Function(Func::Wrap, vec![next, n], Some(span))))
+    }
+
+    pub fn if_unset(target: (bool, String, (usize, usize)), expr: Box<Expr>) -> Assignment {
+        Assignment {
+            targets: vec![target],
+            expr: expr,
+            if_unset: true,
         }
     }
 }
@@ -23,9 +42,20 @@ pub enum Expr {
         local: bool,
         name: String,
     },
-    Function(Func, Vec<Box<Expr>>),
+    /// Function call, with its byte span in the input if it was parsed
+    Function(Func, Vec<Box<Expr>>, Option<(usize,usize)>),
     Op(Box<Expr>, Opcode, Box<Expr>),
     Signed(Sign, Box<Expr>),
+    /// sum_where("prefix"), sum of the global variables starting with prefix
+    SumWhere(String),
+    /// "$hp ?? 100", value of the variable, or of default if it is not set, with its byte span
+    /// in the input if it was parsed
+    VariableOr {
+        local: bool,
+        name: String,
+        default: Box<Expr>,
+        span: Option<(usize,usize)>,
+    },
 }
 
 #[derive(Copy, Clone)]
@@ -35,6 +65,11 @@ pub enum Opcode {
     Multiply,
     Divide,
     Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
 }
 
 #[derive(Copy, Clone)]
@@ -44,6 +79,34 @@ pub enum Func {
     Max,
     Sin,
     Cos,
+    Exp,
+    Exp2,
+    Prev,
+    Monus,
+    Gcd,
+    Lcm,
+    Fract,
+    IsPow2,
+    Popcount,
+    Every,
+    Radians,
+    Degrees,
+    Lerp,
+    Smoothstep,
+    Step,
+    AtLeast,
+    AtMost,
+    Dot,
+    Hypot,
+    AbsDiff,
+    Noise,
+    Avg,
+    CopySign,
+    Clamp01,
+    Triangle,
+    Sawtooth,
+    Poly,
+    Wrap,
 }
 
 #[derive(Copy,Clone)]
@@ -58,7 +121,7 @@ impl Debug for Expr {
         match *self {
             Number(n) => write!(fmt, "{:?}", n),
             Variable {local, ref name} => write!(fmt, "{}{}", if local {""} else {"$"}, name),
-            Function(n, ref params) => {
+            Function(n, ref params, _) => {
                 try!(write!(fmt, "{:?}(", n));
                 let mut has_previous = false;
                 for param in params {
@@ -73,10 +136,79 @@ impl Debug for Expr {
             }
             Op(ref l, op, ref r) => write!(fmt, "({:?} {:?} {:?})", l, op, r),
             Signed(sign, ref e) => write!(fmt, "{:?}({:?})", sign, e),
+            SumWhere(ref prefix) => write!(fmt, "sum_where({:?})", prefix),
+            VariableOr {local, ref name, ref default, ..} => {
+                write!(fmt, "({}{} ?? {:?})", if local {""} else {"$"}, name, default)
+            }
         }
     }
 }
 
+// Unlike Debug, numbers are written so that parsing them gives back the same value
+impl Display for Expr {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
+        use self::Expr::*;
+        match *self {
+            Number(n) => {
+                // Literals are never negative nor infinite, such numbers are written as
+                // expressions giving back the same value
+                if n.is_nan() {
+                    return write!(fmt, "(0 / 0)");
+                }
+                if n.is_infinite() {
+                    return write!(fmt, "({}1 / 0)", if n < 0.0 {"-"} else {""});
+                }
+                if n.is_sign_negative() {
+                    return write!(fmt, "(-{})", Number(-n));
+                }
+                if n != 0.0 && (n >= 1e16 || n < 1e-5) {
+                    write!(fmt, "{:e}", n)
+                } else {
+                    write!(fmt, "{}", n)
+                }
+            }
+            Variable {local, ref name} => write!(fmt, "{}{}", if local {""} else {"$"}, name),
+            Function(n, ref params, _) => {
+                try!(write!(fmt, "{:?}(", n));
+                let mut has_previous = false;
+                for param in params {
+                    if has_previous {
+                        try!(write!(fmt, ", {}", param));
+                    } else {
+                        try!(write!(fmt, "{}", param));
+                        has_previous = true;
+                    }
+                }
+                write!(fmt, ")")
+            }
+            Op(ref l, Opcode::Pow, ref r) => {
+                try!(write!(fmt, "("));
+                try!(write_term(fmt, l));
+                try!(write!(fmt, " ^ "));
+                try!(write_term(fmt, r));
+                write!(fmt, ")")
+            }
+            Op(ref l, op, ref r) => write!(fmt, "({} {:?} {})", l, op, r),
+            Signed(sign, ref e) => write!(fmt, "{:?}({})", sign, e),
+            SumWhere(ref prefix) => write!(fmt, "sum_where({:?})", prefix),
+            VariableOr {local, ref name, ref default, ..} => {
+                try!(write!(fmt, "({}{} ?? ", if local {""} else {"$"}, name));
+                try!(write_term(fmt, default));
+                write!(fmt, ")")
+            }
+        }
+    }
+}
+
+// Writes an expression where the grammar expects a term (operands of "^", defaults of "??"),
+// signed expressions have to be parenthesised there
+fn write_term(fmt: &mut Formatter, expr: &Expr) -> Result<(), Error> {
+    match *expr {
+        Expr::Signed(..) => write!(fmt, "({})", expr),
+        _ => write!(fmt, "{}", expr),
+    }
+}
+
 impl Debug for Sign {
     fn fmt(&self, fmt: &mut Formatter) -> Result<(), Error> {
         use self::Sign::*;
@@ -96,6 +228,11 @@ impl Debug for Opcode {
             Plus => write!(fmt, "+"),
             Minus => write!(fmt, "-"),
             Pow => write!(fmt, "^"),
+            BitAnd => write!(fmt, "&"),
+            BitOr => write!(fmt, "|"),
+            BitXor => write!(fmt, "^^"),
+            Shl => write!(fmt, "<<"),
+            Shr => write!(fmt, ">>"),
         }
     }
 }
@@ -109,6 +246,34 @@ impl Debug for Func {
             Max => write!(fmt, "max"),
             Sin => write!(fmt, "sin"),
             Cos => write!(fmt, "cos"),
+            Exp => write!(fmt, "exp"),
+            Exp2 => write!(fmt, "exp2"),
+            Prev => write!(fmt, "prev"),
+            Monus => write!(fmt, "monus"),
+            Gcd => write!(fmt, "gcd"),
+            Lcm => write!(fmt, "lcm"),
+            Fract => write!(fmt, "frac"),
+            IsPow2 => write!(fmt, "is_pow2"),
+            Popcount => write!(fmt, "popcount"),
+            Every => write!(fmt, "every"),
+            Radians => write!(fmt, "radians"),
+            Degrees => write!(fmt, "degrees"),
+            Lerp => write!(fmt, "lerp"),
+            Smoothstep => write!(fmt, "smoothstep"),
+            Step => write!(fmt, "step"),
+            AtLeast => write!(fmt, "at_least"),
+            AtMost => write!(fmt, "at_most"),
+            Dot => write!(fmt, "dot"),
+            Hypot => write!(fmt, "hypot"),
+            AbsDiff => write!(fmt, "absdiff"),
+            Noise => write!(fmt, "noise"),
+            Avg => write!(fmt, "avg"),
+            CopySign => write!(fmt, "copysign"),
+            Clamp01 => write!(fmt, "clamp01"),
+            Triangle => write!(fmt, "triangle"),
+            Sawtooth => write!(fmt, "sawtooth"),
+            Poly => write!(fmt, "poly"),
+            Wrap => write!(fmt, "wrap"),
         }
     }
 }
@@ -1,6 +1,9 @@
 use std::str::Chars;
+use std::fmt::{self, Display, Formatter};
 
-#[derive(Debug)]
+use super::{ParseError,ParseOptions};
+
+#[derive(Debug,Clone,PartialEq)]
 pub enum Token {
     Ident(String),
     QuotedString(String),
@@ -19,13 +22,146 @@ pub enum Token {
     Multiply,
     Divide,
     Power,
+    DoublePower,
+    Ampersand,
+    Pipe,
+    ShiftLeft,
+    ShiftRight,
     Rand,
     Min,
     Max,
     Sin,
     Cos,
+    Exp,
+    Exp2,
+    Prev,
+    Monus,
+    Gcd,
+    Lcm,
+    Frac,
+    IsPow2,
+    Popcount,
+    Every,
+    Radians,
+    Degrees,
+    Lerp,
+    Smoothstep,
+    Step,
+    SumWhere,
+    AtLeast,
+    AtMost,
+    Dot,
+    Hypot,
+    AbsDiff,
+    Noise,
+    Avg,
+    CopySign,
+    Clamp01,
+    Triangle,
+    Sawtooth,
+    Poly,
+    Wrap,
+    Cycle,
     Equal,
+    QuestionEqual,
+    DoubleQuestion,
     Dollar,
+    At,
+}
+
+impl Token {
+    /// Whether this token can be the last token of an operand
+    fn ends_operand(&self) -> bool {
+        match *self {
+            Token::Ident(_) | Token::Float(_) | Token::RightParenthesis => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this token can be the first token of an operand
+    fn starts_operand(&self) -> bool {
+        match *self {
+            Token::Ident(_) | Token::Float(_) | Token::LeftParenthesis | Token::Dollar |
+            Token::Rand | Token::Min | Token::Max | Token::Sin | Token::Cos |
+            Token::Exp | Token::Exp2 | Token::Prev | Token::Monus | Token::Gcd |
+            Token::Lcm | Token::Frac | Token::IsPow2 | Token::Popcount | Token::Every |
+            Token::Radians | Token::Degrees | Token::Lerp | Token::Smoothstep |
+            Token::Step | Token::SumWhere | Token::AtLeast | Token::AtMost | Token::Dot |
+            Token::Hypot | Token::AbsDiff | Token::Noise | Token::Avg | Token::CopySign |
+            Token::Clamp01 | Token::Triangle | Token::Sawtooth | Token::Poly |
+            Token::Wrap => true,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Token {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Token::Ident(ref name) => write!(fmt, "{}", name),
+            Token::QuotedString(ref string) => write!(fmt, "{:?}", string),
+            Token::Float(value) => write!(fmt, "{}", value),
+            Token::LeftBracket => write!(fmt, "{{"),
+            Token::RightBracket => write!(fmt, "}}"),
+            Token::Comma => write!(fmt, ","),
+            Token::Colon => write!(fmt, ":"),
+            Token::SemiColon => write!(fmt, ";"),
+            Token::LeftParenthesis => write!(fmt, "("),
+            Token::RightParenthesis => write!(fmt, ")"),
+            Token::LeftArray => write!(fmt, "["),
+            Token::RightArray => write!(fmt, "]"),
+            Token::Plus => write!(fmt, "+"),
+            Token::Minus => write!(fmt, "-"),
+            Token::Multiply => write!(fmt, "*"),
+            Token::Divide => write!(fmt, "/"),
+            Token::Power => write!(fmt, "^"),
+            Token::DoublePower => write!(fmt, "^^"),
+            Token::Ampersand => write!(fmt, "&"),
+            Token::Pipe => write!(fmt, "|"),
+            Token::ShiftLeft => write!(fmt, "<<"),
+            Token::ShiftRight => write!(fmt, ">>"),
+            Token::Rand => write!(fmt, "rand"),
+            Token::Min => write!(fmt, "min"),
+            Token::Max => write!(fmt, "max"),
+            Token::Sin => write!(fmt, "sin"),
+            Token::Cos => write!(fmt, "cos"),
+            Token::Exp => write!(fmt, "exp"),
+            Token::Exp2 => write!(fmt, "exp2"),
+            Token::Prev => write!(fmt, "prev"),
+            Token::Monus => write!(fmt, "monus"),
+            Token::Gcd => write!(fmt, "gcd"),
+            Token::Lcm => write!(fmt, "lcm"),
+            Token::Frac => write!(fmt, "frac"),
+            Token::IsPow2 => write!(fmt, "is_pow2"),
+            Token::Popcount => write!(fmt, "popcount"),
+            Token::Every => write!(fmt, "every"),
+            Token::Radians => write!(fmt, "radians"),
+            Token::Degrees => write!(fmt, "degrees"),
+            Token::Lerp => write!(fmt, "lerp"),
+            Token::Smoothstep => write!(fmt, "smoothstep"),
+            Token::Step => write!(fmt, "step"),
+            Token::SumWhere => write!(fmt, "sum_where"),
+            Token::AtLeast => write!(fmt, "at_least"),
+            Token::AtMost => write!(fmt, "at_most"),
+            Token::Dot => write!(fmt, "dot"),
+            Token::Hypot => write!(fmt, "hypot"),
+            Token::AbsDiff => write!(fmt, "absdiff"),
+            Token::Noise => write!(fmt, "noise"),
+            Token::Avg => write!(fmt, "avg"),
+            Token::CopySign => write!(fmt, "copysign"),
+            Token::Clamp01 => write!(fmt, "clamp01"),
+            Token::Triangle => write!(fmt, "triangle"),
+            Token::Sawtooth => write!(fmt, "sawtooth"),
+            Token::Poly => write!(fmt, "poly"),
+            Token::Wrap => write!(fmt, "wrap"),
+            Token::Cycle => write!(fmt, "cycle"),
+            Token::Equal => write!(fmt, "="),
+            Token::QuestionEqual => write!(fmt, "?="),
+            Token::DoubleQuestion => write!(fmt, "??"),
+            Token::Dollar => write!(fmt, "$"),
+            Token::At => write!(fmt, "@"),
+        }
+    }
 }
 
 struct Memory<T: Iterator> {
@@ -74,13 +210,18 @@ where I: Copy,
 }
 
 pub struct Tokenizer<'a> {
+    input: &'a str,
     inner: Memory<Chars<'a>>,
+    // Last token, if it could end an operand (used to detect implicit multiplications)
+    previous_operand: Option<String>,
+    options: ParseOptions,
 }
 
 impl <'a> Iterator for Tokenizer<'a> {
-    type Item = Result<Token,String>;
+    type Item = Result<(usize,Token,usize),ParseError>;
     fn next(&mut self) -> Option<<Self as Iterator>::Item> {
         self.consume_whitespace();
+        let start = self.offset();
         let next = match self.inner.next() {
             None => return None,
             Some(c) => c,
@@ -99,39 +240,123 @@ impl <'a> Iterator for Tokenizer<'a> {
             '-' => Token::Minus,
             '*' => Token::Multiply,
             '/' => Token::Divide,
-            '^' => Token::Power,
+            '^' => {
+                if self.inner.next() == Some('^') {
+                    Token::DoublePower
+                } else {
+                    self.inner.rewind();
+                    Token::Power
+                }
+            }
+            '&' => Token::Ampersand,
+            '|' => Token::Pipe,
+            '<' | '>' => {
+                if self.inner.next() != Some(next) {
+                    self.inner.rewind();
+                    let span = Some((start, self.offset()));
+                    return Some(Err(ParseError::Syntax(format!("Unrecognized character {}", next), span)));
+                }
+                if next == '<' {Token::ShiftLeft} else {Token::ShiftRight}
+            }
             '=' => Token::Equal,
-            '$' => Token::Dollar,
+            '?' => {
+                match self.inner.next() {
+                    Some('=') => Token::QuestionEqual,
+                    Some('?') => Token::DoubleQuestion,
+                    _ => {
+                        self.inner.rewind();
+                        let span = Some((start, self.offset()));
+                        return Some(Err(ParseError::Syntax("Unrecognized character ?".into(), span)));
+                    }
+                }
+            }
+            c if c == self.options.global_sigil => Token::Dollar,
+            '@' => Token::At,
             c if c.is_alphabetic() => {
                 self.inner.rewind();
                 self.parse_word()
             }
             c if c == '"' => {
-                match self.parse_quoted_string() {
+                match self.parse_quoted_string(start) {
                     Ok(token) => token,
                     Err(e) => return Some(Err(e)),
                 }
             }
             c if c.is_numeric() => {
                 self.inner.rewind();
-                Token::Float(self.parse_number())
+                match self.parse_number() {
+                    Ok(number) => Token::Float(number),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            other => {
+                let span = Some((start, self.offset()));
+                return Some(Err(ParseError::Syntax(format!("Unrecognized character {}", other), span)));
+            }
+        };
+        let end = self.offset();
+        // Two operands next to each other, such as "2x" or "(1)(2)"
+        if token.starts_operand() {
+            if let Some(ref previous) = self.previous_operand {
+                let message = format!("missing operator between '{}' and '{}'", previous, token);
+                return Some(Err(ParseError::Syntax(message, Some((start, end)))));
             }
-            other => return Some(Err(format!("Unrecognized character {}", other))),
+        }
+        self.previous_operand = if token.ends_operand() {
+            Some(token.to_string())
+        } else {
+            None
         };
-        Some(Ok(token))
+        Some(Ok((start, token, end)))
     }
 }
 
 impl <'a> Tokenizer<'a> {
     pub fn new(input: &'a str) -> Tokenizer<'a> {
+        Tokenizer::with_options(input, &ParseOptions::default())
+    }
+
+    pub fn with_options(input: &'a str, options: &ParseOptions) -> Tokenizer<'a> {
         Tokenizer {
-            inner: Memory::new(input.chars())
+            input: input,
+            inner: Memory::new(input.chars()),
+            previous_operand: None,
+            options: options.clone(),
         }
     }
 
+    /// Byte offset of the next character to be read
+    fn offset(&self) -> usize {
+        let mut remaining = self.inner.inner.as_str().len();
+        if self.inner.rewind {
+            if let Some(c) = self.inner.last_item {
+                remaining += c.len_utf8();
+            }
+        }
+        self.input.len() - remaining
+    }
+
+    /// Consumes whitespace and line continuations (a backslash followed by "\n" or "\r\n")
     fn consume_whitespace(&mut self) {
-        for _ in self.inner.by_ref().take_while(|&c| c.is_whitespace()) {}
-        self.inner.rewind();
+        loop {
+            for _ in self.inner.by_ref().take_while(|&c| c.is_whitespace()) {}
+            let line_ending = if self.inner.previous() != Some('\\') {
+                0
+            } else if self.inner.inner.as_str().starts_with('\n') {
+                1
+            } else if self.inner.inner.as_str().starts_with("\r\n") {
+                2
+            } else {
+                0
+            };
+            if line_ending == 0 {
+                self.inner.rewind();
+                return;
+            }
+            for _ in 0..line_ending {
+                self.inner.next();
+            }
+        }
     }
 
     fn parse_word(&mut self) -> Token {
@@ -143,21 +368,98 @@ impl <'a> Tokenizer<'a> {
             "max" => return Token::Max,
             "sin" => return Token::Sin,
             "cos" => return Token::Cos,
+            "exp" => return Token::Exp,
+            "exp2" => return Token::Exp2,
+            "prev" => return Token::Prev,
+            "monus" => return Token::Monus,
+            "gcd" => return Token::Gcd,
+            "lcm" => return Token::Lcm,
+            "frac" => return Token::Frac,
+            "is_pow2" => return Token::IsPow2,
+            "popcount" => return Token::Popcount,
+            "every" => return Token::Every,
+            "radians" => return Token::Radians,
+            "degrees" => return Token::Degrees,
+            "lerp" => return Token::Lerp,
+            "smoothstep" => return Token::Smoothstep,
+            "step" => return Token::Step,
+            "sum_where" => return Token::SumWhere,
+            "at_least" => return Token::AtLeast,
+            "at_most" => return Token::AtMost,
+            "dot" => return Token::Dot,
+            "hypot" => return Token::Hypot,
+            "absdiff" => return Token::AbsDiff,
+            "noise" => return Token::Noise,
+            "avg" => return Token::Avg,
+            "copysign" => return Token::CopySign,
+            "clamp01" => return Token::Clamp01,
+            "triangle" => return Token::Triangle,
+            "sawtooth" => return Token::Sawtooth,
+            "poly" => return Token::Poly,
+            "wrap" => return Token::Wrap,
+            "cycle" => return Token::Cycle,
             _ => {}
         }
         assert!(word.len() != 0);
         Token::Ident(word)
     }
 
-    // TODO: Improve to really parse f64
-    fn parse_number(&mut self) -> f64 {
-        let number_str: String = self.inner.by_ref().take_while(|&c| c.is_numeric()).collect();
+    /// Whether the characters following an 'e' in a number are digits, optionally signed
+    fn exponent_follows(&self) -> bool {
+        let mut rest = self.inner.inner.as_str().chars();
+        match rest.next() {
+            Some('+') | Some('-') => rest.next().map_or(false, |c| c.is_numeric()),
+            Some(c) => c.is_numeric(),
+            None => false,
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<f64,ParseError> {
+        let start = self.offset();
+        let mut number_str: String = self.inner.by_ref().take_while(|&c| c.is_numeric()).collect();
+        if self.inner.previous() == Some('.') {
+            number_str.push('.');
+            number_str.extend(self.inner.by_ref().take_while(|&c| c.is_numeric()));
+        }
+        // Without digits after it, the e starts a word, as in "2exp(1)"
+        let exponent = self.inner.previous() == Some('e') || self.inner.previous() == Some('E');
+        if exponent && self.exponent_follows() {
+            number_str.push('e');
+            number_str.extend(self.inner.next());
+            number_str.extend(self.inner.by_ref().take_while(|&c| c.is_numeric()));
+        }
         self.inner.rewind();
-        let number = i64::from_str_radix(&number_str, 10).unwrap();
-        number as f64
+        let span = (start, self.offset());
+        let number: f64 = try!(number_str.parse().map_err(|_| {
+            ParseError::Syntax(format!("Lexer error: invalid number {}", number_str), Some(span))
+        }));
+        let too_large = match self.options.max_literal {
+            Some(max) => number.abs() > max,
+            None => false,
+        };
+        if number.is_infinite() || too_large {
+            return Err(ParseError::NumberOutOfRange(number_str, span, None));
+        }
+        // Angle unit directly following the number, such as 90deg
+        match self.inner.previous() {
+            Some(c) if c.is_alphabetic() => {
+                let suffix_start = self.offset();
+                let suffix: String = self.inner.by_ref().take_while(is_valid_id).collect();
+                self.inner.rewind();
+                match suffix.as_ref() {
+                    "deg" => Ok(number.to_radians()),
+                    "rad" => Ok(number),
+                    _ => {
+                        let message = format!("missing operator between '{}' and '{}'", number_str, suffix);
+                        Err(ParseError::Syntax(message, Some((suffix_start, self.offset()))))
+                    }
+                }
+            }
+            _ => Ok(number),
+        }
     }
 
-    fn parse_quoted_string(&mut self) -> Result<Token,String> {
+    fn parse_quoted_string(&mut self, start: usize) -> Result<Token,ParseError> {
         let mut res = String::new();
         loop {
             res.extend(self.inner.by_ref().take_while(|&c| c != '"' && c != '\\'));
@@ -173,7 +475,8 @@ impl <'a> Tokenizer<'a> {
                             res.push(other);
                         }
                         None => {
-                            return Err(String::from("Lexer error: unfinished quoted string during escape sequence"));
+                            let message = String::from("Lexer error: unfinished quoted string during escape sequence");
+                            return Err(ParseError::Syntax(message, Some((start, self.offset()))));
                         }
                     }
                 },
@@ -183,7 +486,8 @@ impl <'a> Tokenizer<'a> {
                     break;
                 }
                 None => {
-                    return Err(String::from("Lexer error: unfinished quoted string"));
+                    let message = String::from("Lexer error: unfinished quoted string");
+                    return Err(ParseError::Syntax(message, Some((start, self.offset()))));
                 }
             }
         }
@@ -195,3 +499,8 @@ fn is_valid_id(&c: &char) -> bool {
     c.is_alphanumeric() || c == '_' || c == '.'
 }
 
+/// Whether c can prefix global variables, which it cannot if it is part of other tokens
+pub fn is_valid_sigil(c: char) -> bool {
+    !(is_valid_id(&c) || c.is_whitespace() || "{},:;()[]+-*/^&|<>=?\"\\".contains(c))
+}
+
@@ -5,75 +5,501 @@ use self::ast::{
     Sign,
 };
 use expressions::{
+    ExpressionError,
     ExpressionEvaluator,
     ExpressionMember,
     Operator,
     BinaryOperator,
     UnaryOperator,
+    VariadicOperator,
     Variable,
 };
-use rules::RulesEvaluator;
-use self::lexer::Tokenizer;
+use rules::{RulesEvaluator,Instruction};
+use self::lexer::{Tokenizer,is_valid_sigil};
+use lalrpop_util::ParseError as LalrpopError;
+use std::fmt::{self, Display, Formatter};
+use std::collections::HashMap;
+use std::error::Error;
 
 pub use self::ast::Expr;
+pub use self::lexer::Token;
 
-mod ast;
+pub mod ast;
 mod lexer;
 #[allow(dead_code)]
 mod parser;
 
+#[derive(Clone,Debug,PartialEq)]
+pub enum ParseError {
+    /// The input is not a valid rule, with the byte span of the offending token if any
+    Syntax(String, Option<(usize,usize)>),
+    /// The input is well-formed, but does not make sense (e.g. wrong number of arguments), with
+    /// the byte span of the offending construct if any
+    Semantic(String, Option<(usize,usize)>),
+    /// A numeric literal is too large (see ParseOptions::max_literal), with the index of the
+    /// rules it is in when parsing several of them (see parse_rules_multi)
+    NumberOutOfRange(String, (usize,usize), Option<usize>),
+}
+
+impl ParseError {
+    /// Byte span in the input of the token which caused the error, if known
+    pub fn span(&self) -> Option<(usize,usize)> {
+        match *self {
+            ParseError::Syntax(_, span) | ParseError::Semantic(_, span) => span,
+            ParseError::NumberOutOfRange(_, span, _) => Some(span),
+        }
+    }
+
+    /// Line and column (both starting at 1, the column counted in characters) of the start of
+    /// the span in the parsed input, if known and input is long enough to hold it
+    ///
+    /// "\r\n" line endings count as a single line break.
+    pub fn line_column(&self, input: &str) -> Option<(usize,usize)> {
+        let before = match self.span().and_then(|(start, _)| input.get(..start)) {
+            Some(before) => before,
+            None => return None,
+        };
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        Some((line, before[line_start..].chars().count() + 1))
+    }
+}
+
+impl ParseError {
+    // Error in the given rules of a multi-rules input, starting at offset
+    fn in_segment(self, index: usize, offset: usize) -> ParseError {
+        let shift = |(start, end): (usize,usize)| (start + offset, end + offset);
+        match self {
+            ParseError::Syntax(message, span) => {
+                ParseError::Syntax(format!("In rules {}: {}", index, message), span.map(shift))
+            }
+            ParseError::Semantic(message, span) => {
+                ParseError::Semantic(format!("In rules {}: {}", index, message), span.map(shift))
+            }
+            ParseError::NumberOutOfRange(number, span, _) => {
+                ParseError::NumberOutOfRange(number, shift(span), Some(index))
+            }
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ParseError::Syntax(ref message, None) |
+            ParseError::Semantic(ref message, None) => write!(fmt, "{}", message),
+            ParseError::Syntax(ref message, Some((start, _))) |
+            ParseError::Semantic(ref message, Some((start, _))) => write!(fmt, "{} at byte {}", message, start),
+            ParseError::NumberOutOfRange(ref number, (start, _), None) => {
+                write!(fmt, "Number {} is out of range at byte {}", number, start)
+            }
+            ParseError::NumberOutOfRange(ref number, (start, _), Some(index)) => {
+                write!(fmt, "In rules {}: Number {} is out of range at byte {}", index, number, start)
+            }
+        }
+    }
+}
+
+impl From<LalrpopError<usize,Token,ParseError>> for ParseError {
+    fn from(error: LalrpopError<usize,Token,ParseError>) -> ParseError {
+        match error {
+            LalrpopError::User{error} => error,
+            LalrpopError::InvalidToken{location} => {
+                ParseError::Syntax("Invalid token".into(), Some((location, location)))
+            }
+            LalrpopError::UnrecognizedToken{token: Some((start, token, end)), ..} => {
+                ParseError::Syntax(format!("Unexpected token '{}'", token), Some((start, end)))
+            }
+            LalrpopError::UnrecognizedToken{token: None, ..} => {
+                ParseError::Syntax("Unexpected end of input".into(), None)
+            }
+            LalrpopError::ExtraToken{token: (start, token, end)} => {
+                ParseError::Syntax(format!("Extra token '{}'", token), Some((start, end)))
+            }
+        }
+    }
+}
+
+/// Options of parse_rule_with_options
+///
+/// Other parsing functions use the default options, and rules are always written back (by
+/// Display or to_ast) with '$' as the global sigil.
+#[derive(Clone,Debug)]
+pub struct ParseOptions {
+    /// Maximum absolute value of numeric literals (unlimited by default)
+    pub max_literal: Option<f64>,
+    /// Prefix of global variables, '$' by default (with '@', labels cannot be used)
+    ///
+    /// Letters, digits, whitespace and characters used by operators or punctuation are
+    /// rejected.
+    pub global_sigil: char,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            max_literal: None,
+            global_sigil: '$',
+        }
+    }
+}
+
 impl Expr {
-    fn convert(self, res: &mut Vec<ExpressionMember>) {
+    // Local variables named in constants are replaced by their value
+    fn convert(self, res: &mut Vec<ExpressionMember>, constants: &HashMap<String,f64>) -> Result<(),ParseError> {
         match self {
             Expr::Number(num) => {
                 res.push(ExpressionMember::Constant(num));
             }
+            Expr::Variable{local: true, ref name} if constants.contains_key(name) => {
+                res.push(ExpressionMember::Constant(constants[name]));
+            }
             Expr::Variable{local,name} => {
                 res.push(ExpressionMember::Variable(Variable::new(local,name)));
             }
-            Expr::Function(func, args) => {
-                // TODO: insert check on function's number of operands
+            Expr::Function(Func::Prev, mut args, span) => {
+                // The variable is kept in the expression member, only the number of steps is
+                // evaluated on the stack
+                if args.len() != 2 {
+                    return Err(ParseError::Semantic(format!("Function prev expects 2 argument(s), got {}", args.len()), span));
+                }
+                let steps_back = args.pop().unwrap();
+                let variable = match *args.pop().unwrap() {
+                    Expr::Variable{local,name} if !(local && constants.contains_key(&name)) => Variable::new(local,name),
+                    _ => return Err(ParseError::Semantic("The first argument of prev must be a variable".into(), span)),
+                };
+                try!(steps_back.convert(res, constants));
+                res.push(ExpressionMember::Previous(variable));
+            }
+            Expr::Function(func, args, span) => {
+                let nb_args = args.len();
+                let operator = try!(func.to_member(nb_args, span));
                 for arg in args {
-                    arg.convert(res);
+                    try!(arg.convert(res, constants));
                 }
-                let operator = func.into();
                 res.push(operator);
             }
             Expr::Op(l, op, r) => {
-                l.convert(res);
-                r.convert(res);
+                try!(l.convert(res, constants));
+                try!(r.convert(res, constants));
                 let operator = op.into();
                 res.push(operator);
             }
+            Expr::SumWhere(prefix) => {
+                res.push(ExpressionMember::SumGlobals(prefix));
+            }
+            Expr::VariableOr{local, name, default, span} => {
+                // Like prev, the variable stays in the expression member so that it can be
+                // looked up without failing
+                if local && constants.contains_key(&name) {
+                    return Err(ParseError::Semantic(format!("{} is a constant, it is always set", name), span));
+                }
+                try!(default.convert(res, constants));
+                res.push(ExpressionMember::VariableOr(Variable::new(local,name)));
+            }
             Expr::Signed(sign, r) => {
-                r.convert(res);
+                try!(r.convert(res, constants));
                 match sign {
                     Sign::Plus => {}
                     Sign::Minus => res.push(ExpressionMember::Op(Operator::Unary(UnaryOperator::Minus))),
                 }
             }
         }
+        Ok(())
+    }
+}
+
+pub fn parse_rule(input: &str) -> Result<RulesEvaluator,ParseError> {
+    parse_rule_with_options(input, &ParseOptions::default())
+}
+
+pub fn parse_rule_with_options(input: &str, options: &ParseOptions) -> Result<RulesEvaluator,ParseError> {
+    if !is_valid_sigil(options.global_sigil) {
+        let message = format!("'{}' cannot be used as the global sigil", options.global_sigil);
+        return Err(ParseError::Semantic(message, None));
     }
+    let tokenizer = Tokenizer::with_options(input, options);
+    let statements = try!(parser::parse_Rule(tokenizer));
+    convert_rule(statements, &HashMap::new())
 }
 
-pub fn parse_rule(input: &str) -> Result<RulesEvaluator,String> {
+/// Parses a rule where the local variables named in constants are replaced by their value
+///
+/// Assigning to one of these constants is an error.
+pub fn parse_rule_with_constants(input: &str, constants: &HashMap<String,f64>) -> Result<RulesEvaluator,ParseError> {
     let tokenizer = Tokenizer::new(input);
-    let tokenizer_mapped = tokenizer.map(|e| {
-        e.map(|token| ((),token,()))
-    });
-    let assignments = match parser::parse_Rule(tokenizer_mapped) {
-        Ok(t) => t,
-        Err(e) => {
-            return Err(format!("Parsing error {:?}", e));
-        }
-    };
+    let statements = try!(parser::parse_Rule(tokenizer));
+    convert_rule(statements, constants)
+}
+
+fn convert_rule(statements: Vec<(Option<String>, Assignment)>, constants: &HashMap<String,f64>) -> Result<RulesEvaluator,ParseError> {
+    let mut res = RulesEvaluator::new(Vec::new());
+    for instruction in try!(convert_statements(statements, constants)) {
+        res.push(instruction);
+    }
+    Ok(res)
+}
+
+fn convert_statements(statements: Vec<(Option<String>, Assignment)>, constants: &HashMap<String,f64>) -> Result<Vec<Instruction>,ParseError> {
+    let mut res = Vec::new();
+    for (label, assignment) in statements {
+        if let Some(label) = label {
+            res.push(Instruction::Label(label));
+        }
+        res.extend(try!(assignment.convert(constants)));
+    }
+    Ok(res)
+}
+
+/// Parses several independent rules, separated by lines containing only "---"
+///
+/// Error spans refer to the whole input, and messages tell which rules (counting from 0) are
+/// invalid.
+pub fn parse_rules_multi(input: &str) -> Result<Vec<RulesEvaluator>,ParseError> {
     let mut res = Vec::new();
-    for Assignment{local, variable, expr} in assignments {
+    let mut start = 0;
+    let mut offset = 0;
+    for line in input.split('\n') {
+        let end = offset + line.len();
+        if line.trim() == "---" {
+            let index = res.len();
+            res.push(try!(parse_rule(&input[start..offset]).map_err(|e| e.in_segment(index, start))));
+            start = (end + 1).min(input.len());
+        }
+        offset = end + 1;
+    }
+    let index = res.len();
+    res.push(try!(parse_rule(&input[start..]).map_err(|e| e.in_segment(index, start))));
+    Ok(res)
+}
+
+/// Parses statements, such as a line typed in a REPL, to be pushed on an existing
+/// RulesEvaluator
+///
+/// The input can hold several statements and labels, and a statement can give several
+/// instructions, such as a chained assignment.
+pub fn parse_instruction(input: &str) -> Result<Vec<Instruction>,ParseError> {
+    let tokenizer = Tokenizer::new(input);
+    let statements = try!(parser::parse_Rule(tokenizer));
+    convert_statements(statements, &HashMap::new())
+}
+
+/// Splits the input into tokens, along with their byte span in the input
+pub fn tokenize(input: &str) -> Result<Vec<(usize,Token,usize)>,ParseError> {
+    Tokenizer::new(input).collect()
+}
+
+/// Error of eval, while either parsing or evaluating the expression
+#[derive(Clone,Debug)]
+pub enum EvalError {
+    Parse(ParseError),
+    Expression(ExpressionError),
+}
+
+impl Display for EvalError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            EvalError::Parse(ref err) => write!(fmt, "{}", err),
+            EvalError::Expression(ref err) => write!(fmt, "{}", err),
+        }
+    }
+}
+
+impl Error for EvalError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            EvalError::Expression(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for EvalError {
+    fn from(err: ParseError) -> EvalError {
+        EvalError::Parse(err)
+    }
+}
+
+impl From<ExpressionError> for EvalError {
+    fn from(err: ExpressionError) -> EvalError {
+        EvalError::Expression(err)
+    }
+}
+
+/// Parses a single expression, such as "x * 2 + 1", and evaluates it
+///
+/// Both local and global variables ("x" and "$x") are read from variables.
+pub fn eval(input: &str, variables: &HashMap<String,f64>) -> Result<f64,EvalError> {
+    let tokenizer = Tokenizer::new(input);
+    let expr = try!(parser::parse_Expr(tokenizer).map_err(ParseError::from));
+    let mut members = Vec::new();
+    try!(expr.convert(&mut members, &HashMap::new()));
+    Ok(try!(ExpressionEvaluator::new(members).evaluate_flat(variables)))
+}
+
+/// Whether the input is a complete set of statements: every statement is terminated, and
+/// parentheses, brackets and braces are balanced
+///
+/// This lets a REPL keep reading lines until the input can be parsed. Inputs with a lexical
+/// error are considered complete, so that parsing them reports the error.
+pub fn is_complete(input: &str) -> bool {
+    let mut depth = 0;
+    let mut last = None;
+    for item in Tokenizer::new(input) {
+        let token = match item {
+            Ok((_, token, _)) => token,
+            Err(_) => return true,
+        };
+        match token {
+            Token::LeftParenthesis | Token::LeftBracket | Token::LeftArray => depth += 1,
+            Token::RightParenthesis | Token::RightBracket | Token::RightArray => depth -= 1,
+            _ => {}
+        }
+        last = Some(token);
+    }
+    depth <= 0 && (last.is_none() || last == Some(Token::SemiColon))
+}
+
+impl Assignment {
+    // "a = b = expr;" is converted to "b = expr; a = b;", evaluating expr only once
+    fn convert(self, constants: &HashMap<String,f64>) -> Result<Vec<Instruction>,ParseError> {
+        let Assignment{mut targets, expr, if_unset} = self;
+        for &(local, ref name, span) in targets.iter() {
+            // Set after every instruction (see RulesEvaluator::evaluate)
+            if local && name == "ans" {
+                return Err(ParseError::Syntax("'ans' is a reserved keyword".into(), Some(span)));
+            }
+            if local && constants.contains_key(name) {
+                return Err(ParseError::Semantic(format!("Cannot assign to the constant {}", name), Some(span)));
+            }
+        }
         let mut vec = Vec::new();
-        expr.convert(&mut vec);
-        res.push((Variable{local:local, name:variable}, ExpressionEvaluator::new(vec)));
+        try!(expr.convert(&mut vec, constants));
+        let (local, name, _) = match targets.pop() {
+            Some(target) => target,
+            None => return Ok(vec![Instruction::Expression(ExpressionEvaluator::new(vec))]),
+        };
+        if if_unset {
+            // The grammar only allows a single target
+            return Ok(vec![Instruction::AssignmentIfUnset(Variable::new(local, name), ExpressionEvaluator::new(vec))]);
+        }
+        let mut previous = Variable::new(local, name);
+        let mut instructions = vec![Instruction::Assignment(previous.clone(), ExpressionEvaluator::new(vec))];
+        while let Some((local, name, _)) = targets.pop() {
+            let variable = Variable::new(local, name);
+            let expression = ExpressionEvaluator::new(vec![ExpressionMember::Variable(previous)]);
+            instructions.push(Instruction::Assignment(variable.clone(), expression));
+            previous = variable;
+        }
+        Ok(instructions)
     }
-    Ok(RulesEvaluator::new(res))
+}
+
+impl ExpressionEvaluator {
+    /// Rebuilds the syntax tree of the expression from its postfix form
+    pub fn to_ast(&self) -> Result<Box<Expr>,ExpressionError> {
+        let mut stack = Vec::new();
+        for member in self.members() {
+            let node = match *member {
+                ExpressionMember::Constant(value) => Expr::Number(value),
+                ExpressionMember::Variable(Variable{local,ref name}) => {
+                    Expr::Variable{local: local, name: name.clone()}
+                }
+                ExpressionMember::SumGlobals(ref prefix) => Expr::SumWhere(prefix.clone()),
+                ExpressionMember::VariableOr(Variable{local,ref name}) => {
+                    let default = try!(pop_node(&mut stack));
+                    Expr::VariableOr{local: local, name: name.clone(), default: default, span: None}
+                }
+                ExpressionMember::Previous(Variable{local,ref name}) => {
+                    let steps_back = try!(pop_node(&mut stack));
+                    let variable = Box::new(Expr::Variable{local: local, name: name.clone()});
+                    Expr::Function(Func::Prev, vec![variable, steps_back], None)
+                }
+                ExpressionMember::Op(Operator::Unary(op)) => {
+                    let operand = try!(pop_node(&mut stack));
+                    match op {
+                        UnaryOperator::Minus => Expr::Signed(Sign::Minus, operand),
+                        UnaryOperator::Sin => Expr::Function(Func::Sin, vec![operand], None),
+                        UnaryOperator::Cos => Expr::Function(Func::Cos, vec![operand], None),
+                        UnaryOperator::Exp => Expr::Function(Func::Exp, vec![operand], None),
+                        UnaryOperator::Exp2 => Expr::Function(Func::Exp2, vec![operand], None),
+                        UnaryOperator::Fract => Expr::Function(Func::Fract, vec![operand], None),
+                        UnaryOperator::IsPow2 => Expr::Function(Func::IsPow2, vec![operand], None),
+                        UnaryOperator::Popcount => Expr::Function(Func::Popcount, vec![operand], None),
+                        UnaryOperator::Radians => Expr::Function(Func::Radians, vec![operand], None),
+                        UnaryOperator::Degrees => Expr::Function(Func::Degrees, vec![operand], None),
+                        UnaryOperator::Noise => Expr::Function(Func::Noise, vec![operand], None),
+                        UnaryOperator::Clamp01 => Expr::Function(Func::Clamp01, vec![operand], None),
+                    }
+                }
+                ExpressionMember::Op(Operator::Binary(op)) => {
+                    let rhs = try!(pop_node(&mut stack));
+                    let lhs = try!(pop_node(&mut stack));
+                    match op {
+                        BinaryOperator::Plus => Expr::Op(lhs, Opcode::Plus, rhs),
+                        BinaryOperator::Minus => Expr::Op(lhs, Opcode::Minus, rhs),
+                        BinaryOperator::Multiply => Expr::Op(lhs, Opcode::Multiply, rhs),
+                        BinaryOperator::Divide => Expr::Op(lhs, Opcode::Divide, rhs),
+                        BinaryOperator::Pow => Expr::Op(lhs, Opcode::Pow, rhs),
+                        BinaryOperator::BitAnd => Expr::Op(lhs, Opcode::BitAnd, rhs),
+                        BinaryOperator::BitOr => Expr::Op(lhs, Opcode::BitOr, rhs),
+                        BinaryOperator::BitXor => Expr::Op(lhs, Opcode::BitXor, rhs),
+                        BinaryOperator::Shl => Expr::Op(lhs, Opcode::Shl, rhs),
+                        BinaryOperator::Shr => Expr::Op(lhs, Opcode::Shr, rhs),
+                        BinaryOperator::Min => Expr::Function(Func::Min, vec![lhs, rhs], None),
+                        BinaryOperator::Max => Expr::Function(Func::Max, vec![lhs, rhs], None),
+                        BinaryOperator::Rand => Expr::Function(Func::Rand, vec![lhs, rhs], None),
+                        BinaryOperator::Monus => Expr::Function(Func::Monus, vec![lhs, rhs], None),
+                        BinaryOperator::Gcd => Expr::Function(Func::Gcd, vec![lhs, rhs], None),
+                        BinaryOperator::Lcm => Expr::Function(Func::Lcm, vec![lhs, rhs], None),
+                        BinaryOperator::Every => Expr::Function(Func::Every, vec![lhs, rhs], None),
+                        BinaryOperator::Step => Expr::Function(Func::Step, vec![lhs, rhs], None),
+                        BinaryOperator::Hypot => Expr::Function(Func::Hypot, vec![lhs, rhs], None),
+                        BinaryOperator::AbsDiff => Expr::Function(Func::AbsDiff, vec![lhs, rhs], None),
+                        BinaryOperator::CopySign => Expr::Function(Func::CopySign, vec![lhs, rhs], None),
+                        BinaryOperator::Triangle => Expr::Function(Func::Triangle, vec![lhs, rhs], None),
+                        BinaryOperator::Sawtooth => Expr::Function(Func::Sawtooth, vec![lhs, rhs], None),
+                        BinaryOperator::Wrap => Expr::Function(Func::Wrap, vec![lhs, rhs], None),
+                    }
+                }
+                ExpressionMember::Op(Operator::Variadic(op, nb_operands)) => {
+                    if nb_operands == 0 || stack.len() < nb_operands {
+                        return Err(ExpressionError::InvalidExpression(format!("Missing member for operator {:?}", op).into()));
+                    }
+                    let start = stack.len() - nb_operands;
+                    let args = stack.split_off(start);
+                    match op {
+                        VariadicOperator::Min => Expr::Function(Func::Min, args, None),
+                        VariadicOperator::Max => Expr::Function(Func::Max, args, None),
+                        VariadicOperator::Lerp => Expr::Function(Func::Lerp, args, None),
+                        VariadicOperator::Smoothstep => Expr::Function(Func::Smoothstep, args, None),
+                        VariadicOperator::Dot => Expr::Function(Func::Dot, args, None),
+                        VariadicOperator::Avg => Expr::Function(Func::Avg, args, None),
+                        VariadicOperator::Poly => Expr::Function(Func::Poly, args, None),
+                    }
+                }
+            };
+            stack.push(Box::new(node));
+        }
+        let result = try!(pop_node(&mut stack));
+        if !stack.is_empty() {
+            return Err(ExpressionError::InvalidExpression("Stack not empty at the end of the expression".into()));
+        }
+        Ok(result)
+    }
+}
+
+impl Display for ExpressionEvaluator {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match self.to_ast() {
+            Ok(ast) => write!(fmt, "{}", ast),
+            Err(_) => write!(fmt, "<invalid expression>"),
+        }
+    }
+}
+
+fn pop_node(stack: &mut Vec<Box<Expr>>) -> Result<Box<Expr>,ExpressionError> {
+    stack.pop().ok_or_else(|| ExpressionError::InvalidExpression("Missing member in the expression".into()))
 }
 
 impl Into<ExpressionMember> for Opcode {
@@ -85,40 +511,90 @@ impl Into<ExpressionMember> for Opcode {
             Multiply => ExpressionMember::Op(Operator::Binary(BinaryOperator::Multiply)),
             Divide => ExpressionMember::Op(Operator::Binary(BinaryOperator::Divide)),
             Pow => ExpressionMember::Op(Operator::Binary(BinaryOperator::Pow)),
+            BitAnd => ExpressionMember::Op(Operator::Binary(BinaryOperator::BitAnd)),
+            BitOr => ExpressionMember::Op(Operator::Binary(BinaryOperator::BitOr)),
+            BitXor => ExpressionMember::Op(Operator::Binary(BinaryOperator::BitXor)),
+            Shl => ExpressionMember::Op(Operator::Binary(BinaryOperator::Shl)),
+            Shr => ExpressionMember::Op(Operator::Binary(BinaryOperator::Shr)),
         }
     }
 }
-impl Into<ExpressionMember> for Func {
-    fn into(self) -> ExpressionMember {
+impl Func {
+    /// Checks the number of arguments of the function, and gives the matching operator
+    fn to_member(self, nb_args: usize, span: Option<(usize,usize)>) -> Result<ExpressionMember,ParseError> {
         use self::ast::Func::*;
-        match self {
-            Sin => ExpressionMember::Op(Operator::Unary(UnaryOperator::Sin)),
-            Cos => ExpressionMember::Op(Operator::Unary(UnaryOperator::Cos)),
-            Min => ExpressionMember::Op(Operator::Binary(BinaryOperator::Min)),
-            Max => ExpressionMember::Op(Operator::Binary(BinaryOperator::Max)),
-            Rand => ExpressionMember::Op(Operator::Binary(BinaryOperator::Rand)),
+        let (operator, min_args, max_args) = match self {
+            Sin => (Operator::Unary(UnaryOperator::Sin), 1, Some(1)),
+            Cos => (Operator::Unary(UnaryOperator::Cos), 1, Some(1)),
+            Exp => (Operator::Unary(UnaryOperator::Exp), 1, Some(1)),
+            Exp2 => (Operator::Unary(UnaryOperator::Exp2), 1, Some(1)),
+            Min => (Operator::Variadic(VariadicOperator::Min, nb_args), 1, None),
+            Max => (Operator::Variadic(VariadicOperator::Max, nb_args), 1, None),
+            Rand => (Operator::Binary(BinaryOperator::Rand), 2, Some(2)),
+            Prev => unreachable!("prev() is converted by Expr::convert"),
+            Monus => (Operator::Binary(BinaryOperator::Monus), 2, Some(2)),
+            Gcd => (Operator::Binary(BinaryOperator::Gcd), 2, Some(2)),
+            Lcm => (Operator::Binary(BinaryOperator::Lcm), 2, Some(2)),
+            Fract => (Operator::Unary(UnaryOperator::Fract), 1, Some(1)),
+            IsPow2 => (Operator::Unary(UnaryOperator::IsPow2), 1, Some(1)),
+            Popcount => (Operator::Unary(UnaryOperator::Popcount), 1, Some(1)),
+            Every => (Operator::Binary(BinaryOperator::Every), 2, Some(2)),
+            Radians => (Operator::Unary(UnaryOperator::Radians), 1, Some(1)),
+            Degrees => (Operator::Unary(UnaryOperator::Degrees), 1, Some(1)),
+            Lerp => (Operator::Variadic(VariadicOperator::Lerp, nb_args), 3, Some(3)),
+            Smoothstep => (Operator::Variadic(VariadicOperator::Smoothstep, nb_args), 3, Some(3)),
+            Step => (Operator::Binary(BinaryOperator::Step), 2, Some(2)),
+            AtLeast => (Operator::Binary(BinaryOperator::Max), 2, Some(2)),
+            AtMost => (Operator::Binary(BinaryOperator::Min), 2, Some(2)),
+            Dot => (Operator::Variadic(VariadicOperator::Dot, nb_args), 2, None),
+            Hypot => (Operator::Binary(BinaryOperator::Hypot), 2, Some(2)),
+            AbsDiff => (Operator::Binary(BinaryOperator::AbsDiff), 2, Some(2)),
+            Noise => (Operator::Unary(UnaryOperator::Noise), 1, Some(1)),
+            Avg => (Operator::Variadic(VariadicOperator::Avg, nb_args), 1, None),
+            CopySign => (Operator::Binary(BinaryOperator::CopySign), 2, Some(2)),
+            Clamp01 => (Operator::Unary(UnaryOperator::Clamp01), 1, Some(1)),
+            Triangle => (Operator::Binary(BinaryOperator::Triangle), 2, Some(2)),
+            Sawtooth => (Operator::Binary(BinaryOperator::Sawtooth), 2, Some(2)),
+            Poly => (Operator::Variadic(VariadicOperator::Poly, nb_args), 2, None),
+            Wrap => (Operator::Binary(BinaryOperator::Wrap), 2, Some(2)),
+        };
+        if nb_args < min_args {
+            return Err(ParseError::Semantic(format!("Function {:?} expects at least {} argument(s), got {}", self, min_args, nb_args), span));
+        }
+        if let Some(max_args) = max_args {
+            if nb_args > max_args {
+                return Err(ParseError::Semantic(format!("Function {:?} expects at most {} argument(s), got {}", self, max_args, nb_args), span));
+            }
+        }
+        if let Dot = self {
+            if nb_args % 2 != 0 {
+                return Err(ParseError::Semantic(format!("Function dot expects an even number of arguments, got {}", nb_args), span));
+            }
         }
+        Ok(ExpressionMember::Op(operator))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    use super::{parse_rule,parse_rules_multi,parse_rule_with_options,parse_rule_with_constants,parse_instruction,tokenize,is_complete,eval,EvalError,ParseError,ParseOptions};
     use super::ast::Expr;
     use super::lexer::Tokenizer;
-    use expressions::ExpressionEvaluator;
+    use expressions::{ExpressionEvaluator,ExpressionMember,ExpressionError};
+    use rules::Instruction;
 
     fn parse_expr_to_ast(input: &str) -> Option<Box<Expr>> {
         let tokenizer = Tokenizer::new(input);
-        let tokenizer_mapped = tokenizer.map(|e| {
-            e.map(|token| ((),token,()))
-        });
-        super::parser::parse_Expr(tokenizer_mapped).ok()
+        super::parser::parse_Expr(tokenizer).ok()
     }
 
     fn parse_expr(input: &str) -> ExpressionEvaluator {
         let mut vec = vec![];
         let ast = parse_expr_to_ast(input).unwrap();
-        ast.convert(&mut vec);
+        ast.convert(&mut vec, &HashMap::new()).unwrap();
         ExpressionEvaluator::new(vec)
     }
 
@@ -200,6 +676,22 @@ mod tests {
         assert!(parse_expr_to_ast("Point.Test").is_some());
     }
 
+    #[test]
+    fn implicit_multiplication() {
+        assert_eq!(parse_rule("a = 2x;").unwrap_err(),
+                   ParseError::Syntax("missing operator between '2' and 'x'".into(), Some((5, 6))));
+        assert_eq!(parse_rule("a = 2 sin(x);").unwrap_err(),
+                   ParseError::Syntax("missing operator between '2' and 'sin'".into(), Some((6, 9))));
+        assert_eq!(parse_rule("a = (1)(2);").unwrap_err(),
+                   ParseError::Syntax("missing operator between ')' and '('".into(), Some((7, 8))));
+        assert_eq!(parse_rule("a = 2exp(1);").unwrap_err(),
+                   ParseError::Syntax("missing operator between '2' and 'exp'".into(), Some((5, 8))));
+        assert_eq!(parse_rule("a = 2e;").unwrap_err(),
+                   ParseError::Syntax("missing operator between '2' and 'e'".into(), Some((5, 6))));
+        assert!(parse_rule("a = 2 * x;").is_ok());
+        assert_eq!(parse_expr("2e-1 + 2E+1").evaluate(&(), &()).unwrap(), 20.2);
+    }
+
     // Test the evaluation
     #[test]
     fn evaluation() {
@@ -208,4 +700,668 @@ mod tests {
         let res = parse_expr("-1-2-3").evaluate(&(), &()).unwrap();
         assert_eq!(res, -6.0);
     }
+
+    #[test]
+    fn crlf_line_endings() {
+        let lf = "a = 42;\nb = 10;\n$intelligence = a*2+b;\n";
+        let crlf = lf.replace("\n", "\r\n");
+        let mut lf_variables = HashMap::new();
+        parse_rule(lf).unwrap().evaluate(&mut lf_variables).unwrap();
+        let mut crlf_variables = HashMap::new();
+        parse_rule(&crlf).unwrap().evaluate(&mut crlf_variables).unwrap();
+        assert_eq!(lf_variables, crlf_variables);
+        assert_eq!(crlf_variables.get("intelligence"), Some(&94.0));
+    }
+
+    #[test]
+    fn crlf_error_position() {
+        let lf = "a = 42;\nb = 10 +;\n";
+        let crlf = lf.replace("\n", "\r\n");
+        let lf_error = parse_rule(lf).unwrap_err();
+        let crlf_error = parse_rule(&crlf).unwrap_err();
+        assert_eq!(crlf_error.span(), Some((17, 18)));
+        assert_eq!(lf_error.line_column(lf), Some((2, 9)));
+        assert_eq!(crlf_error.line_column(&crlf), Some((2, 9)));
+        // Not the parsed input
+        assert_eq!(crlf_error.line_column("a = 42;"), None);
+        assert_eq!(ParseError::Syntax("é".into(), Some((1, 2))).line_column("é"), None);
+    }
+
+    #[test]
+    fn incremental_parsing() {
+        let lines = ["a = 42;", "b = 10;", "$intelligence = a*2+b;"];
+        let mut incremental = parse_rule("").unwrap();
+        for line in lines.iter() {
+            for instruction in parse_instruction(line).unwrap() {
+                incremental.push(instruction);
+            }
+        }
+        let mut incremental_variables = HashMap::new();
+        incremental.evaluate(&mut incremental_variables).unwrap();
+        let mut full_variables = HashMap::new();
+        parse_rule(&lines.join("\n")).unwrap().evaluate(&mut full_variables).unwrap();
+        assert_eq!(incremental_variables, full_variables);
+        assert_eq!(parse_instruction("a = b = 2;").unwrap().len(), 2);
+        // A line can hold several statements, and labels
+        assert_eq!(parse_instruction("a = 1; b = 2;").unwrap().len(), 2);
+        let instructions = parse_instruction("@setup: x = 1;").unwrap();
+        assert_eq!(instructions.len(), 2);
+        match instructions[0] {
+            Instruction::Label(ref label) => assert_eq!(label, "setup"),
+            ref other => panic!("Unexpected instruction {:?}", other),
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(parse_expr("1.5").evaluate(&(), &()).unwrap(), 1.5);
+        assert_eq!(parse_expr("1.5e2").evaluate(&(), &()).unwrap(), 150.0);
+        assert_eq!(parse_expr("25E-2").evaluate(&(), &()).unwrap(), 0.25);
+        assert!(parse_rule("a = 1e;").is_err());
+    }
+
+    #[test]
+    fn number_out_of_range() {
+        assert_eq!(parse_rule("a = 1e400;").unwrap_err(), ParseError::NumberOutOfRange("1e400".into(), (4, 9), None));
+        let options = ParseOptions { max_literal: Some(1000.0), ..ParseOptions::default() };
+        assert_eq!(parse_rule_with_options("a = -5000;", &options).unwrap_err(),
+                   ParseError::NumberOutOfRange("5000".into(), (5, 9), None));
+        assert!(parse_rule_with_options("a = 500;", &options).is_ok());
+        assert!(parse_rule("a = 5000;").is_ok());
+    }
+
+    #[test]
+    fn token_spans() {
+        let input = "x = 1;";
+        let tokens = tokenize(input).unwrap();
+        let substrings: Vec<&str> = tokens.iter().map(|&(start, _, end)| &input[start..end]).collect();
+        assert_eq!(substrings, vec!["x", "=", "1", ";"]);
+
+        // Offsets are in bytes, "é" is two bytes long
+        assert_eq!(parse_rule("é = 1 + #;").unwrap_err().span(), Some((9, 10)));
+        assert_eq!(parse_rule("a = 1 ;;").unwrap_err().span(), Some((7, 8)));
+    }
+
+    #[test]
+    fn exponential() {
+        assert_eq!(parse_expr("exp(0)").evaluate(&(), &()).unwrap(), 1.0);
+        let e = parse_expr("exp(1)").evaluate(&(), &()).unwrap();
+        assert!((e - ::std::f64::consts::E).abs() < 1e-12);
+        assert_eq!(parse_expr("exp2(10)").evaluate(&(), &()).unwrap(), 1024.0);
+    }
+
+    #[test]
+    fn previous_value() {
+        assert!(parse_rule("a = prev($hp, 1);").is_ok());
+        assert!(parse_rule("a = prev(hp + 1, 1);").is_err());
+        assert!(parse_rule("a = prev($hp);").is_err());
+    }
+
+    #[test]
+    fn to_ast() {
+        let inputs = ["1 + 2 * 3", "-(1 - x) ^ 2", "min($a, 2, sin(b))", "rand(1, 2) / cos(3)", "prev($hp, 1)"];
+        for input in inputs.iter() {
+            let ast = parse_expr(input).to_ast().unwrap();
+            assert_eq!(format!("{:?}", ast), format!("{:?}", parse_expr_to_ast(input).unwrap()));
+        }
+        let incorrect = ExpressionEvaluator::new(vec![ExpressionMember::Constant(1.0),
+                                                      ExpressionMember::Constant(2.0)]);
+        assert!(incorrect.to_ast().is_err());
+    }
+
+    #[test]
+    fn angle_units() {
+        let right_angle = parse_expr("90deg").evaluate(&(), &()).unwrap();
+        assert!((right_angle - 1.5708).abs() < 1e-4);
+        let sinus = parse_expr("sin(90deg)").evaluate(&(), &()).unwrap();
+        assert!((sinus - 1.0).abs() < 1e-12);
+        assert_eq!(parse_expr("1.57rad").evaluate(&(), &()).unwrap(), 1.57);
+        assert!(parse_rule("a = 90 deg;").is_err());
+        assert!(parse_rule("a = 90degrees;").is_err());
+    }
+
+    #[test]
+    fn reserved_keywords() {
+        assert_eq!(parse_rule("sin = 2;").unwrap_err(),
+                   ParseError::Syntax("'sin' is a reserved keyword".into(), Some((0, 3))));
+        assert_eq!(parse_rule("x = 1;\n$max = x;").unwrap_err(),
+                   ParseError::Syntax("'max' is a reserved keyword".into(), Some((8, 11))));
+        assert_eq!(parse_rule("sin ?= 1;").unwrap_err(),
+                   ParseError::Syntax("'sin' is a reserved keyword".into(), Some((0, 3))));
+        assert_eq!(parse_rule("a = sin = 1;").unwrap_err(),
+                   ParseError::Syntax("'sin' is a reserved keyword".into(), Some((4, 7))));
+        assert_eq!(parse_rule("sum_where = 1;").unwrap_err(),
+                   ParseError::Syntax("'sum_where' is a reserved keyword".into(), Some((0, 9))));
+        assert_eq!(parse_rule("$cycle = 1;").unwrap_err(),
+                   ParseError::Syntax("'cycle' is a reserved keyword".into(), Some((1, 6))));
+        assert_eq!(parse_rule("cycle(cos, 3);").unwrap_err(),
+                   ParseError::Syntax("'cos' is a reserved keyword".into(), Some((6, 9))));
+        assert!(parse_rule("x = 3;").is_ok());
+    }
+
+    #[test]
+    fn function_names_are_not_variables() {
+        // Functions added later (step, dot, avg, wrap, every, noise, cycle) took their names
+        // from the variable namespace
+        for name in ["step", "dot", "avg", "wrap", "every", "noise", "cycle"].iter() {
+            let error = parse_rule(&format!("${} = ${} + 1;", name, name)).unwrap_err();
+            assert_eq!(error, ParseError::Syntax(format!("'{}' is a reserved keyword", name), Some((1, 1 + name.len()))));
+            assert!(parse_rule(&format!("a = {} + 1;", name)).is_err());
+        }
+    }
+
+    #[test]
+    fn monus() {
+        assert_eq!(parse_expr("monus(5, 3)").evaluate(&(), &()).unwrap(), 2.0);
+        assert_eq!(parse_expr("monus(3, 5)").evaluate(&(), &()).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn gcd_lcm() {
+        assert_eq!(parse_expr("gcd(12, 18)").evaluate(&(), &()).unwrap(), 6.0);
+        assert_eq!(parse_expr("lcm(4, 6)").evaluate(&(), &()).unwrap(), 12.0);
+        // Absolute values of the integer parts are used
+        assert_eq!(parse_expr("gcd(-12, 18.7)").evaluate(&(), &()).unwrap(), 6.0);
+        assert_eq!(parse_expr("gcd(0, 0)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("gcd(0, 5)").evaluate(&(), &()).unwrap(), 5.0);
+        assert_eq!(parse_expr("lcm(0, 5)").evaluate(&(), &()).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let expression = parse_expr("0.1 + 0.2");
+        assert_eq!(expression.to_string(), "(0.1 + 0.2)");
+        assert_eq!(parse_expr("x ^ (-y)").to_string(), "(x ^ (-(y)))");
+        for input in ["0.1 + 0.2", "0.0000001 * x", "1e300 / 3", "-(2.5 ^ 1e-20)",
+                      "x ^ (-y)", "x ^ (+y)", "(-x) ^ 2"].iter() {
+            let expression = parse_expr(input);
+            let reparsed = parse_expr(&expression.to_string());
+            assert_eq!(format!("{:?}", reparsed.members()), format!("{:?}", expression.members()));
+        }
+    }
+
+    #[test]
+    fn display_round_trip_special_numbers() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), 4.0);
+        let mut expression = parse_expr("x ^ 2");
+        expression.set_constant(1, -2.0).unwrap();
+        assert_eq!(expression.to_string(), "(x ^ (-2))");
+        assert_eq!(parse_expr(&expression.to_string()).evaluate(&(), &variables).unwrap(), 0.0625);
+        let values = [::std::f64::INFINITY, ::std::f64::NEG_INFINITY, -0.0, -1e-20];
+        for &value in values.iter() {
+            let expression = ExpressionEvaluator::new(vec![ExpressionMember::Constant(value)]);
+            let result = parse_expr(&expression.to_string()).evaluate(&(), &()).unwrap();
+            assert_eq!(result, value);
+            assert_eq!(result.is_sign_negative(), value.is_sign_negative());
+        }
+        let nan = ExpressionEvaluator::new(vec![ExpressionMember::Constant(::std::f64::NAN)]);
+        assert!(parse_expr(&nan.to_string()).evaluate(&(), &()).unwrap().is_nan());
+    }
+
+    #[test]
+    fn fractional_part() {
+        assert_eq!(parse_expr("frac(2.75)").evaluate(&(), &()).unwrap(), 0.75);
+        assert_eq!(parse_expr("frac(-2.75)").evaluate(&(), &()).unwrap(), -0.75);
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let mut variables = HashMap::new();
+        parse_rule("$a = $b = 0;").unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("a"), Some(&0.0));
+        assert_eq!(variables.get("b"), Some(&0.0));
+
+        let mut variables = HashMap::new();
+        parse_rule("$a = b = 3; $c = b;").unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("a"), Some(&3.0));
+        assert_eq!(variables.get("b"), None);
+        assert_eq!(variables.get("c"), Some(&3.0));
+
+        let mut variables = HashMap::new();
+        parse_rule("$x = y = $z = 2 + 5;").unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("x"), Some(&7.0));
+        assert_eq!(variables.get("y"), None);
+        assert_eq!(variables.get("z"), Some(&7.0));
+    }
+
+    #[test]
+    fn bit_functions() {
+        assert_eq!(parse_expr("is_pow2(8)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("is_pow2(6)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("is_pow2(0)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("popcount(7)").evaluate(&(), &()).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn every() {
+        assert_eq!(parse_expr("every(6, 3)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("every(7, 3)").evaluate(&(), &()).unwrap(), 0.0);
+        assert!(parse_expr("every(7, 0)").evaluate(&(), &()).unwrap().is_nan());
+    }
+
+    #[test]
+    fn line_continuation() {
+        let mut variables = HashMap::new();
+        parse_rule("$a = 1 + \\\n2;\n$b = 3 * \\\r\n  4;").unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("a"), Some(&3.0));
+        assert_eq!(variables.get("b"), Some(&12.0));
+        // Positions after a continuation still refer to the original input
+        assert_eq!(parse_rule("a = 1 + \\\n #;").unwrap_err().span(), Some((11, 12)));
+        assert!(parse_rule("a = 1 \\ + 2;").is_err());
+        // Continuations are skipped without recursion, however many of them follow each other
+        let long = format!("$c = 1 + {}2;", "\\\n".repeat(200000));
+        parse_rule(&long).unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("c"), Some(&3.0));
+    }
+
+    #[test]
+    fn complete_input() {
+        assert!(is_complete("a = 1;\n$b = a * 2;\n"));
+        assert!(is_complete(""));
+        assert!(!is_complete("a = 1"));
+        assert!(!is_complete("a = min(1,\n"));
+        assert!(!is_complete("a = (1;"));
+    }
+
+    #[test]
+    fn constants() {
+        let mut constants = HashMap::new();
+        constants.insert("GRAVITY".to_string(), 9.81);
+        let rules = parse_rule_with_constants("$weight = mass * GRAVITY;", &constants).unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("mass".to_string(), 2.0);
+        // mass is not a constant, and stays a local variable
+        assert!(rules.evaluate(&mut variables).is_err());
+        let rules = parse_rule_with_constants("$weight = $mass * GRAVITY;", &constants).unwrap();
+        rules.evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("weight"), Some(&19.62));
+        assert!(parse_rule_with_constants("GRAVITY = 10;", &constants).is_err());
+    }
+
+    #[test]
+    fn expression_constants() {
+        let mut constants = HashMap::new();
+        constants.insert("pi".to_string(), ::std::f64::consts::PI);
+        let mut vec = vec![];
+        parse_expr_to_ast("2 * pi + 1").unwrap().convert(&mut vec, &constants).unwrap();
+        assert_eq!(ExpressionEvaluator::new(vec).constants(), vec![2.0, ::std::f64::consts::PI, 1.0]);
+        assert_eq!(parse_expr("2 * x + 1").constants(), vec![2.0, 1.0]);
+    }
+
+    #[test]
+    fn risky_divisions() {
+        let mut ranges = HashMap::new();
+        ranges.insert("x".to_string(), (0.0, 10.0));
+        let expression = parse_expr("1 / (x - 5)");
+        assert_eq!(expression.risky_divisions(&HashMap::new(), &ranges).unwrap(), vec!["(x - 5)".to_string()]);
+        let expression = parse_expr("1 / (x + 10)");
+        assert!(expression.risky_divisions(&HashMap::new(), &ranges).unwrap().is_empty());
+        let expression = parse_expr("x / 2 + 3 / x");
+        assert_eq!(expression.risky_divisions(&HashMap::new(), &ranges).unwrap(), vec!["x".to_string()]);
+        assert!(expression.risky_divisions(&HashMap::new(), &HashMap::new()).is_err());
+        ranges.insert("y".to_string(), (-1.0, 1.0));
+        let expression = parse_expr("x / (0 * (1 / y))");
+        assert_eq!(expression.risky_divisions(&HashMap::new(), &ranges).unwrap(),
+                   vec!["y".to_string(), "(0 * (1 / y))".to_string()]);
+    }
+
+    #[test]
+    fn fixpoint() {
+        let x = parse_expr("cos(x)").fixpoint("x", 1.0, 1e-9, 100, &()).unwrap();
+        assert!((x - 0.739085).abs() < 1e-6);
+        match parse_expr("x + 1").fixpoint("x", 0.0, 1e-9, 100, &()) {
+            Err(ExpressionError::NotConverged(value)) => assert_eq!(value, 100.0),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn canonicalize() {
+        fn canonical(input: &str) -> String {
+            format!("{:?}", parse_expr(input).canonicalize().members())
+        }
+        assert_eq!(canonical("a + b"), canonical("b + a"));
+        assert_eq!(canonical("max(x * 2, y, 1) + c"), canonical("c + max(1, y, 2 * x)"));
+        assert!(canonical("a - b") != canonical("b - a"));
+        assert!(canonical("a / (b + c)") != canonical("(c + b) / a"));
+        assert_eq!(parse_expr("(b - a) * 2").canonicalize().to_string(), "(2 * (b - a))");
+        // Reordering the operands of min and max does not change how NaN is handled
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), ::std::f64::NAN);
+        for input in ["min(x, 1)", "min(1, x)", "max(x, 1)", "max(1, x)", "min(x, 1, 2)", "max(2, x, 1)"].iter() {
+            let expression = parse_expr(input);
+            let result = expression.evaluate(&(), &variables).unwrap();
+            assert_eq!(expression.canonicalize().evaluate(&(), &variables).unwrap(), result);
+            assert!(!result.is_nan());
+        }
+    }
+
+    #[test]
+    fn eval_expression() {
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), 3.0);
+        assert_eq!(eval("x * 2 + 1", &variables).unwrap(), 7.0);
+        assert_eq!(eval("$x - 1", &variables).unwrap(), 2.0);
+        match eval("x * (2 + 1", &variables) {
+            Err(EvalError::Parse(ParseError::Syntax(..))) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        match eval("y * 2", &variables) {
+            Err(EvalError::Expression(ExpressionError::VariableNotFound(ref name))) => assert_eq!(name, "y"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(eval("x = 1;", &variables).is_err());
+        assert_eq!(eval("y * 2", &variables).unwrap_err().source().unwrap().to_string(), "Variable y not found");
+        assert!(eval("x * (2 + 1", &variables).unwrap_err().source().is_none());
+    }
+
+    #[test]
+    fn set_constant() {
+        let mut expression = parse_expr("x * 2 + 1");
+        let positions = expression.constant_positions();
+        assert_eq!(positions, vec![1, 3]);
+        expression.set_constant(positions[0], 3.0).unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), 5.0);
+        assert_eq!(expression.evaluate(&(), &variables).unwrap(), 16.0);
+        // x is not a constant
+        assert!(expression.set_constant(0, 3.0).is_err());
+        assert!(expression.set_constant(10, 3.0).is_err());
+    }
+
+    #[test]
+    fn angle_conversions() {
+        use std::f64::consts::PI;
+        assert!((parse_expr("radians(180)").evaluate(&(), &()).unwrap() - PI).abs() < 1e-12);
+        assert!((parse_expr("degrees(3.141592653589793)").evaluate(&(), &()).unwrap() - 180.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn lerp() {
+        assert_eq!(parse_expr("lerp(0, 10, 0.5)").evaluate(&(), &()).unwrap(), 5.0);
+        assert_eq!(parse_expr("lerp(0, 10, 2)").evaluate(&(), &()).unwrap(), 20.0);
+        assert!(parse_rule("a = lerp(0, 10);").is_err());
+    }
+
+    #[test]
+    fn smoothstep() {
+        assert_eq!(parse_expr("smoothstep(0, 1, 0.5)").evaluate(&(), &()).unwrap(), 0.5);
+        assert_eq!(parse_expr("smoothstep(0, 1, 0)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("smoothstep(0, 1, 1)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("smoothstep(0, 1, -3)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("smoothstep(0, 1, 3)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("smoothstep(2, 2, 1)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("smoothstep(2, 2, 2)").evaluate(&(), &()).unwrap(), 1.0);
+    }
+
+    #[test]
+    fn depth() {
+        assert_eq!(parse_expr("1").depth(), 1);
+        assert_eq!(parse_expr("1 + 2").depth(), 2);
+        assert_eq!(parse_expr("1 + 2 * 3").depth(), 3);
+        assert_eq!(parse_expr("max(1, -2, 3 * 4)").depth(), 3);
+    }
+
+    #[test]
+    fn step() {
+        assert_eq!(parse_expr("step(7, 5)").evaluate(&(), &()).unwrap(), 5.0);
+        assert_eq!(parse_expr("step(8, 5)").evaluate(&(), &()).unwrap(), 10.0);
+        match parse_expr("step(8, 0)").evaluate(&(), &()) {
+            Err(ExpressionError::InvalidExpression(ref message)) => assert_eq!(message, "step size of step(x, s) is 0"),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sum_where() {
+        let mut variables = HashMap::new();
+        variables.insert("stat_a".to_string(), 3.0);
+        variables.insert("stat_b".to_string(), 4.5);
+        variables.insert("other".to_string(), 100.0);
+        parse_rule("$total = sum_where(\"stat_\") * 2;").unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("total"), Some(&15.0));
+        assert_eq!(parse_expr("sum_where(\"none\")").evaluate_flat(&variables).unwrap(), 0.0);
+        assert_eq!(parse_expr("sum_where(\"stat_\") + 1").to_string(), "(sum_where(\"stat_\") + 1)");
+    }
+
+    #[test]
+    fn one_sided_clamps() {
+        assert_eq!(parse_expr("at_least(-5, 0)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("at_least(5, 0)").evaluate(&(), &()).unwrap(), 5.0);
+        assert_eq!(parse_expr("at_most(150, 100)").evaluate(&(), &()).unwrap(), 100.0);
+        assert!(parse_rule("a = at_most(1, 2, 3);").is_err());
+    }
+
+    #[test]
+    fn multiple_rules() {
+        let rules = parse_rules_multi("$a = 1;\n---\n$b = a;\n").unwrap();
+        assert_eq!(rules.len(), 2);
+        let mut variables = HashMap::new();
+        rules[0].evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("a"), Some(&1.0));
+        // Local variables of the first rules are not visible in the second ones
+        assert!(rules[1].evaluate(&mut variables).is_err());
+
+        let error = parse_rules_multi("$a = 1;\n---\n$b = #;\n").unwrap_err();
+        assert_eq!(error.span(), Some((17, 18)));
+        assert!(error.to_string().starts_with("In rules 1: "));
+        let error = parse_rules_multi("$a = 1;\n---\n$b = 1e400;\n").unwrap_err();
+        assert_eq!(error, ParseError::NumberOutOfRange("1e400".into(), (17, 22), Some(1)));
+        assert_eq!(error.to_string(), "In rules 1: Number 1e400 is out of range at byte 17");
+    }
+
+    #[test]
+    fn global_sigil() {
+        let options = ParseOptions { global_sigil: '@', ..ParseOptions::default() };
+        let mut variables = HashMap::new();
+        variables.insert("global".to_string(), 2.0);
+        parse_rule_with_options("local = 3; @result = @global + local;", &options).unwrap().evaluate(&mut variables).unwrap();
+        assert_eq!(variables.get("result"), Some(&5.0));
+        assert!(parse_rule_with_options("$result = 1;", &options).is_err());
+        let options = ParseOptions { global_sigil: '#', ..ParseOptions::default() };
+        assert!(parse_rule_with_options("#result = 1;", &options).is_ok());
+        for &sigil in ['(', '=', '7', 'g', ' ', '_'].iter() {
+            let options = ParseOptions { global_sigil: sigil, ..ParseOptions::default() };
+            let message = format!("'{}' cannot be used as the global sigil", sigil);
+            assert_eq!(parse_rule_with_options("x = 1;", &options).unwrap_err(), ParseError::Semantic(message, None));
+        }
+    }
+
+    #[test]
+    fn assignment_if_unset() {
+        assert!(parse_rule("x ?= 5;").is_ok());
+        assert!(parse_rule("x ?= y ?= 5;").is_err());
+        assert_eq!(parse_rule("x ? 5;").unwrap_err().span(), Some((2, 3)));
+    }
+
+    #[test]
+    fn evaluate_interval() {
+        let mut ranges = HashMap::new();
+        ranges.insert("x".to_string(), (0.0, 10.0));
+        ranges.insert("y".to_string(), (-1.0, 1.0));
+        let globals = HashMap::new();
+        assert_eq!(parse_expr("x + 1").evaluate_interval(&globals, &ranges).unwrap(), (1.0, 11.0));
+        assert_eq!(parse_expr("-x / 2 + sin(y)").evaluate_interval(&globals, &ranges).unwrap(), (-6.0, 1.0));
+        // 0 * inf is undefined, the product can be anything
+        assert_eq!(parse_expr("0 * (1 / y)").evaluate_interval(&globals, &ranges).unwrap(),
+                   (::std::f64::NEG_INFINITY, ::std::f64::INFINITY));
+        assert!(parse_expr("x + z").evaluate_interval(&globals, &ranges).is_err());
+        let mut ranges = HashMap::new();
+        ranges.insert("x".to_string(), (-1.0, 1.0));
+        ranges.insert("y".to_string(), (-1.0, 1.0));
+        assert_eq!(parse_expr("x * y").evaluate_interval(&globals, &ranges).unwrap(), (-1.0, 1.0));
+    }
+
+    #[test]
+    fn dot() {
+        assert_eq!(parse_expr("dot(1, 2, 3, 4)").evaluate(&(), &()).unwrap(), 11.0);
+        assert_eq!(parse_expr("dot(2, 3)").evaluate(&(), &()).unwrap(), 6.0);
+        assert!(parse_rule("a = dot(1, 2, 3);").is_err());
+    }
+
+    #[test]
+    fn hypot_absdiff() {
+        assert_eq!(parse_expr("hypot(3, 4)").evaluate(&(), &()).unwrap(), 5.0);
+        assert_eq!(parse_expr("hypot(1e300, 1e300)").evaluate(&(), &()).unwrap(), 1e300 * 2.0f64.sqrt());
+        assert_eq!(parse_expr("absdiff(2, 5)").evaluate(&(), &()).unwrap(), 3.0);
+        assert_eq!(parse_expr("absdiff(5, 2)").evaluate(&(), &()).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn noise() {
+        let value = parse_expr("noise(5)").evaluate(&(), &()).unwrap();
+        assert!(value >= 0.0 && value < 1.0);
+        assert_eq!(parse_expr("noise(5)").evaluate(&(), &()).unwrap(), value);
+        assert!(parse_expr("noise(6)").evaluate(&(), &()).unwrap() != value);
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_eq!(parse_expr("6 & 3").evaluate(&(), &()).unwrap(), 2.0);
+        assert_eq!(parse_expr("6 | 1").evaluate(&(), &()).unwrap(), 7.0);
+        assert_eq!(parse_expr("5 ^^ 1").evaluate(&(), &()).unwrap(), 4.0);
+        // Operands are truncated
+        assert_eq!(parse_expr("6.9 & 3.5").evaluate(&(), &()).unwrap(), 2.0);
+        // Lower precedence than arithmetic operators
+        assert_eq!(parse_expr("1 + 2 & 2").evaluate(&(), &()).unwrap(), 2.0);
+        assert_eq!(parse_expr("2 ^ 2 ^^ 1").evaluate(&(), &()).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn shift_operators() {
+        assert_eq!(parse_expr("1 << 4").evaluate(&(), &()).unwrap(), 16.0);
+        assert_eq!(parse_expr("256 >> 2").evaluate(&(), &()).unwrap(), 64.0);
+        assert_eq!(parse_expr("-256 >> 2").evaluate(&(), &()).unwrap(), -64.0);
+        // Shifts by 64 or more
+        assert_eq!(parse_expr("1 << 64").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("256 >> 100").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("-256 >> 100").evaluate(&(), &()).unwrap(), -1.0);
+        assert!(parse_expr("1 << -1").evaluate(&(), &()).unwrap().is_nan());
+        // Between arithmetic and bitwise operators
+        assert_eq!(parse_expr("1 + 1 << 2 | 1").evaluate(&(), &()).unwrap(), 9.0);
+        assert!(parse_rule("a = 1 < 2;").is_err());
+    }
+
+    #[test]
+    fn avg() {
+        assert_eq!(parse_expr("avg(2, 4, 6)").evaluate(&(), &()).unwrap(), 4.0);
+        assert_eq!(parse_expr("avg(10)").evaluate(&(), &()).unwrap(), 10.0);
+        assert!(parse_rule("a = avg();").is_err());
+    }
+
+    #[test]
+    fn variable_or() {
+        let expression = parse_expr("$hp ?? 100");
+        let mut variables = HashMap::new();
+        assert_eq!(expression.evaluate(&variables, &()).unwrap(), 100.0);
+        variables.insert("hp".to_string(), 42.0);
+        assert_eq!(expression.evaluate(&variables, &()).unwrap(), 42.0);
+        assert_eq!(expression.get_global_variable_list(), vec!["hp".to_string()]);
+        assert_eq!(parse_expr("$hp ?? 100 * 2").evaluate(&(), &()).unwrap(), 200.0);
+        assert_eq!(parse_expr("$hp ?? 100 * 2").to_string(), "(($hp ?? 100) * 2)");
+        assert!(parse_rule("a = 1 ?? 2;").is_err());
+        let signed = parse_expr("$hp ?? (-1)");
+        assert_eq!(signed.to_string(), "($hp ?? (-(1)))");
+        assert_eq!(parse_expr(&signed.to_string()).evaluate(&(), &()).unwrap(), -1.0);
+        let mut constants = HashMap::new();
+        constants.insert("HP".to_string(), 1.0);
+        let error = parse_rule_with_constants("a = 1; b = HP ?? 2;", &constants).unwrap_err();
+        assert_eq!(error.span(), Some((11, 18)));
+    }
+
+    #[test]
+    fn copysign() {
+        assert_eq!(parse_expr("copysign(3, -1)").evaluate(&(), &()).unwrap(), -3.0);
+        assert_eq!(parse_expr("copysign(-3, 1)").evaluate(&(), &()).unwrap(), 3.0);
+        assert_eq!(parse_expr("copysign(-3, -0.5)").evaluate(&(), &()).unwrap(), -3.0);
+    }
+
+    #[test]
+    fn clamp01() {
+        assert_eq!(parse_expr("clamp01(-0.5)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("clamp01(1.5)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("clamp01(0.3)").evaluate(&(), &()).unwrap(), 0.3);
+    }
+
+    #[test]
+    fn periodic_waves() {
+        assert_eq!(parse_expr("sawtooth(0, 4)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("sawtooth(1, 4)").evaluate(&(), &()).unwrap(), 0.25);
+        assert_eq!(parse_expr("sawtooth(7, 4)").evaluate(&(), &()).unwrap(), 0.75);
+        assert_eq!(parse_expr("sawtooth(-1, 4)").evaluate(&(), &()).unwrap(), 0.75);
+        assert_eq!(parse_expr("triangle(0, 4)").evaluate(&(), &()).unwrap(), 0.0);
+        assert_eq!(parse_expr("triangle(1, 4)").evaluate(&(), &()).unwrap(), 0.5);
+        assert_eq!(parse_expr("triangle(2, 4)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("triangle(3, 4)").evaluate(&(), &()).unwrap(), 0.5);
+        assert_eq!(parse_expr("triangle(4, 4)").evaluate(&(), &()).unwrap(), 0.0);
+        assert!(parse_expr("sawtooth(1, 0)").evaluate(&(), &()).unwrap().is_nan());
+        assert!(parse_expr("triangle(1, -4)").evaluate(&(), &()).unwrap().is_nan());
+    }
+
+    #[test]
+    fn poly() {
+        assert_eq!(parse_expr("poly(2, 1, 0, 1)").evaluate(&(), &()).unwrap(), 5.0);
+        let mut variables = HashMap::new();
+        variables.insert("x".to_string(), 7.0);
+        assert_eq!(parse_expr("poly(x, 3)").evaluate(&(), &variables).unwrap(), 3.0);
+        assert!(parse_rule("a = poly(2);").is_err());
+    }
+
+    #[test]
+    fn strict_integers() {
+        let mut expression = parse_expr("1e30 & 1");
+        // Saturates by default
+        assert!(expression.evaluate(&(), &()).is_ok());
+        expression.set_strict_integers(true);
+        match expression.evaluate(&(), &()) {
+            Err(ExpressionError::IntegerOverflow(value)) => assert_eq!(value, 1e30),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        let mut expression = parse_expr("gcd(12, 18) + 1e30");
+        expression.set_strict_integers(true);
+        assert_eq!(expression.evaluate(&(), &()).unwrap(), 1e30);
+    }
+
+    #[test]
+    fn wrap() {
+        assert_eq!(parse_expr("wrap(7, 3)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("wrap(-1, 3)").evaluate(&(), &()).unwrap(), 2.0);
+        assert_eq!(parse_expr("wrap(2.5, 1)").evaluate(&(), &()).unwrap(), 0.5);
+        assert!(parse_expr("wrap(1, 0)").evaluate(&(), &()).unwrap().is_nan());
+    }
+
+    #[test]
+    fn cycle() {
+        let rules = parse_rule("cycle($phase, 3);").unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("phase".to_string(), 0.0);
+        let mut phases = Vec::new();
+        for _ in 0..4 {
+            rules.evaluate(&mut variables).unwrap();
+            phases.push(variables["phase"]);
+        }
+        assert_eq!(phases, vec![1.0, 2.0, 0.0, 1.0]);
+        assert!(parse_rule("cycle(1, 3);").is_err());
+    }
+
+    #[test]
+    fn variadic_min_max() {
+        assert_eq!(parse_expr("min(3, 1, 2)").evaluate(&(), &()).unwrap(), 1.0);
+        assert_eq!(parse_expr("max(3, 1, 2)").evaluate(&(), &()).unwrap(), 3.0);
+        assert_eq!(parse_expr("min(5)").evaluate(&(), &()).unwrap(), 5.0);
+        assert!(parse_rule("a = min();").is_err());
+        assert!(parse_rule("a = max();").is_err());
+    }
+
+    #[test]
+    fn function_arity() {
+        assert_eq!(parse_rule("a = sin(1, 2);").unwrap_err(),
+                   ParseError::Semantic("Function sin expects at most 1 argument(s), got 2".into(), Some((4, 13))));
+        let mut constants = HashMap::new();
+        constants.insert("GRAVITY".to_string(), 9.81);
+        assert_eq!(parse_rule_with_constants("a = 1; GRAVITY = 10;", &constants).unwrap_err().span(), Some((7, 14)));
+        assert!(parse_rule("a = rand(1);").is_err());
+        assert!(parse_rule("a = rand(1, 2);").is_ok());
+    }
 }
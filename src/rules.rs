@@ -1,16 +1,64 @@
 use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::time::Instant;
 
 use expressions::*;
 
+#[derive(Clone,Debug)]
+pub enum Instruction {
+    Assignment(Variable,ExpressionEvaluator),
+    /// Assignment skipped when the variable is already set ("x ?= 5;")
+    AssignmentIfUnset(Variable,ExpressionEvaluator),
+    /// Bare expression, its value is the result of the rules if it is the last instruction
+    Expression(ExpressionEvaluator),
+    /// Start of the instructions evaluated by evaluate_label, up to the next label
+    Label(String),
+}
+
 #[derive(Clone,Debug)]
 pub struct RulesEvaluator {
-    expressions: Vec<(Variable,ExpressionEvaluator)>,
+    instructions: Vec<Instruction>,
+    // Read back every global variable after setting it
+    verify_writes: bool,
 }
 
 #[derive(Clone,Debug)]
 pub enum RulesError {
     Expression(ExpressionError),
     CannotSetVariable(String),
+    /// No label with this name in the rules
+    UnknownLabel(String),
+    /// The store did not give back the value written to a global variable
+    WriteMismatch { name: String, written: f64, read: Option<f64> },
+    /// The deadline given to evaluate_with_deadline passed
+    Timeout,
+}
+
+impl Display for RulesError {
+    fn fmt(&self, fmt: &mut Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            RulesError::Expression(ref err) => write!(fmt, "{}", err),
+            RulesError::CannotSetVariable(ref name) => write!(fmt, "Cannot set global variable {}", name),
+            RulesError::UnknownLabel(ref label) => write!(fmt, "Unknown label {}", label),
+            RulesError::WriteMismatch { ref name, written, read: Some(read) } => {
+                write!(fmt, "Global variable {} was set to {} but reads back as {}", name, written, read)
+            }
+            RulesError::WriteMismatch { ref name, written, read: None } => {
+                write!(fmt, "Global variable {} was set to {} but cannot be read back", name, written)
+            }
+            RulesError::Timeout => write!(fmt, "Evaluation did not finish before the deadline"),
+        }
+    }
+}
+
+impl Error for RulesError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            RulesError::Expression(ref err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<ExpressionError> for RulesError {
@@ -20,23 +68,520 @@ impl From<ExpressionError> for RulesError {
 }
 
 impl RulesEvaluator {
-    pub fn evaluate<T: Store>(&self, global: &mut T) -> Result<(),RulesError> {
-        let mut local_variables = HashMap::new();
-        for &(Variable{local,ref name},ref expression) in self.expressions.iter() {
-            let res = try!(expression.evaluate(global, &local_variables));
-            if local {
-                local_variables.insert(name.to_string(), res);
-            } else {
-                let result = global.set_attribute(name, res);
-                if result.is_err() {
-                    return Err(RulesError::CannotSetVariable(name.to_string()));
+    /// Evaluates every instruction in order, and returns the value of the last instruction if
+    /// it is a bare expression
+    ///
+    /// After each assignment or bare expression, its value can be read through the local
+    /// variable "ans" in the following instructions of the same rules, which is why rules cannot
+    /// assign to it.
+    ///
+    /// Labels are ignored, every instruction is evaluated.
+    pub fn evaluate<T: Store>(&self, global: &mut T) -> Result<Option<f64>,RulesError> {
+        self.evaluate_instructions(&self.instructions, global, &mut HashMap::new())
+    }
+
+    /// Same as evaluate, but uses locals to hold the local variables
+    ///
+    /// locals is cleared first, so reusing the same map across evaluations only saves its
+    /// allocation, and gives the same results as evaluate.
+    pub fn evaluate_with_locals<T: Store>(&self, global: &mut T, locals: &mut HashMap<String,f64>) -> Result<Option<f64>,RulesError> {
+        self.evaluate_instructions(&self.instructions, global, locals)
+    }
+
+    /// Evaluates only the instructions following "@label:", up to the next label
+    pub fn evaluate_label<T: Store>(&self, global: &mut T, label: &str) -> Result<Option<f64>,RulesError> {
+        let start = match self.instructions.iter().position(|instruction| is_label(instruction, Some(label))) {
+            Some(position) => position + 1,
+            None => return Err(RulesError::UnknownLabel(label.to_string())),
+        };
+        let end = match self.instructions[start..].iter().position(|instruction| is_label(instruction, None)) {
+            Some(position) => start + position,
+            None => self.instructions.len(),
+        };
+        self.evaluate_instructions(&self.instructions[start..end], global, &mut HashMap::new())
+    }
+
+    fn evaluate_instructions<T: Store>(&self, instructions: &[Instruction], global: &mut T, local_variables: &mut HashMap<String,f64>) -> Result<Option<f64>,RulesError> {
+        self.evaluate_instructions_reporting(instructions, global, local_variables, &mut |_| Ok(()), &mut |_, e| Err(e))
+    }
+
+    // Same as evaluate_instructions, calling before_instruction with the index of each
+    // instruction before evaluating it, and on_error with the error of each failed instruction
+    //
+    // An error returned by either of them stops the evaluation.
+    fn evaluate_instructions_reporting<T,B,E>(&self, instructions: &[Instruction], global: &mut T, local_variables: &mut HashMap<String,f64>, before_instruction: &mut B, on_error: &mut E) -> Result<Option<f64>,RulesError>
+    where T: Store,
+          B: FnMut(usize) -> Result<(),RulesError>,
+          E: FnMut(usize, RulesError) -> Result<(),RulesError> {
+        local_variables.clear();
+        let mut result = None;
+        for (index, instruction) in instructions.iter().enumerate() {
+            try!(before_instruction(index));
+            if let Err(e) = self.evaluate_instruction(instruction, global, local_variables, &mut result) {
+                try!(on_error(index, e));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Same as evaluate, but fails with Timeout if the deadline passes before the evaluation
+    /// is over
+    ///
+    /// The clock is checked before each instruction, so global may be partially updated.
+    pub fn evaluate_with_deadline<T: Store>(&self, global: &mut T, deadline: Instant) -> Result<Option<f64>,RulesError> {
+        let mut check_deadline = |_| if Instant::now() >= deadline {
+            Err(RulesError::Timeout)
+        } else {
+            Ok(())
+        };
+        self.evaluate_instructions_reporting(&self.instructions, global, &mut HashMap::new(), &mut check_deadline, &mut |_, e| Err(e))
+    }
+
+    /// Evaluates every instruction, skipping the ones which fail instead of stopping at the
+    /// first error, and returns the errors with the index of the failing instruction
+    ///
+    /// A failed assignment leaves its target unset, so the following instructions reading it
+    /// fail as well.
+    pub fn evaluate_all<T: Store>(&self, global: &mut T) -> Vec<(usize, RulesError)> {
+        let mut errors = Vec::new();
+        {
+            let mut record = |index, e| {
+                errors.push((index, e));
+                Ok(())
+            };
+            // Never fails, every error is recorded
+            let _ = self.evaluate_instructions_reporting(&self.instructions, global, &mut HashMap::new(), &mut |_| Ok(()), &mut record);
+        }
+        errors
+    }
+
+    // Evaluates a single instruction, setting result to the value of a bare expression, or to
+    // None after an assignment
+    fn evaluate_instruction<T: Store>(&self, instruction: &Instruction, global: &mut T, local_variables: &mut HashMap<String,f64>, result: &mut Option<f64>) -> Result<(),RulesError> {
+        match *instruction {
+            Instruction::Assignment(Variable{local,ref name},ref expression) |
+            Instruction::AssignmentIfUnset(Variable{local,ref name},ref expression) => {
+                *result = None;
+                if let Instruction::AssignmentIfUnset(..) = *instruction {
+                    let current = if local {
+                        local_variables.get_attribute(name)
+                    } else {
+                        global.get_attribute(name)
+                    };
+                    if current.is_some() {
+                        return Ok(());
+                    }
                 }
+                let res = try!(expression.evaluate(global, local_variables));
+                if local {
+                    local_variables.insert(name.to_string(), res);
+                } else {
+                    let result = global.set_attribute(name, res);
+                    if result.is_err() {
+                        return Err(RulesError::CannotSetVariable(name.to_string()));
+                    }
+                    if self.verify_writes {
+                        match global.get_attribute(name) {
+                            Some(read) if read == res || (read.is_nan() && res.is_nan()) => {}
+                            read => {
+                                return Err(RulesError::WriteMismatch { name: name.to_string(), written: res, read: read });
+                            }
+                        }
+                    }
+                }
+                local_variables.insert("ans".to_string(), res);
+            }
+            Instruction::Expression(ref expression) => {
+                let res = try!(expression.evaluate(global, local_variables));
+                local_variables.insert("ans".to_string(), res);
+                *result = Some(res);
             }
+            Instruction::Label(_) => {}
         }
         Ok(())
     }
 
+    /// Evaluates the rules without modifying global, and returns the global variables they
+    /// would have set
+    pub fn dry_run<U: Store>(&self, global: &U) -> Result<HashMap<String,f64>,RulesError> {
+        let mut changes = HashMap::new();
+        {
+            // Writes go to changes, reads fall back to global
+            let mut overlay = Layered::new(&mut changes, vec![global as &Store]);
+            try!(self.evaluate(&mut overlay));
+        }
+        Ok(changes)
+    }
+
+    /// Same as evaluate, with read-only inputs in addition to the global variables
+    ///
+    /// Global variables are read from global first, then from inputs. Assignments always write
+    /// to global, and fail with CannotSetVariable for a variable which is set in inputs.
+    pub fn evaluate_with_inputs<T: Store, I: Store>(&self, global: &mut T, inputs: &I) -> Result<Option<f64>,RulesError> {
+        let mut store = WithInputs {
+            layers: Layered::new(global, vec![inputs as &Store]),
+            inputs: inputs,
+        };
+        self.evaluate(&mut store)
+    }
+
+    /// Evaluates the rules on a copy of global, which replaces global only if every instruction
+    /// succeeded
+    ///
+    /// On error, global is left untouched. The whole store is cloned on each call, which is why
+    /// it must implement Clone.
+    pub fn evaluate_transactional<U: Store + Clone>(&self, global: &mut U) -> Result<Option<f64>,RulesError> {
+        let mut copy = global.clone();
+        let result = try!(self.evaluate(&mut copy));
+        *global = copy;
+        Ok(result)
+    }
+
+    /// Dependencies between variables, as (assigned, read) pairs for each variable read by each
+    /// assignment, without duplicates
+    ///
+    /// Global variables are prefixed with "$", as in the rules. A sum_where("prefix") reads every
+    /// global variable starting with prefix, which is written "$prefix*".
+    pub fn dependency_edges(&self) -> Vec<(String, String)> {
+        let mut edges = Vec::new();
+        for instruction in self.instructions.iter() {
+            let (target, expression) = match *instruction {
+                Instruction::Assignment(ref target, ref expression) |
+                Instruction::AssignmentIfUnset(ref target, ref expression) => (target, expression),
+                _ => continue,
+            };
+            for member in expression.members() {
+                match *member {
+                    ExpressionMember::Variable(ref variable) |
+                    ExpressionMember::Previous(ref variable) |
+                    ExpressionMember::VariableOr(ref variable) => {
+                        let edge = (source_name(target), source_name(variable));
+                        if !edges.contains(&edge) {
+                            edges.push(edge);
+                        }
+                    }
+                    ExpressionMember::SumGlobals(ref prefix) => {
+                        let edge = (source_name(target), format!("${}*", prefix));
+                        if !edges.contains(&edge) {
+                            edges.push(edge);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        edges
+    }
+
+    /// Renames every reference to the variable "from" in the given namespace, including
+    /// assignment targets
+    pub fn rename_variable(&mut self, from: &str, to: &str, local: bool) {
+        for instruction in self.instructions.iter_mut() {
+            match *instruction {
+                Instruction::Assignment(ref mut variable, ref mut expression) |
+                Instruction::AssignmentIfUnset(ref mut variable, ref mut expression) => {
+                    if variable.local == local && variable.name == from {
+                        variable.name = to.to_string();
+                    }
+                    expression.rename_variable(from, to, local);
+                }
+                Instruction::Expression(ref mut expression) => {
+                    expression.rename_variable(from, to, local);
+                }
+                Instruction::Label(_) => {}
+            }
+        }
+    }
+
+    /// Reads back every global variable after setting it, and fails with WriteMismatch if the
+    /// store altered the value (for instance by rounding it)
+    ///
+    /// This doubles the number of accesses to the store, and is disabled by default.
+    pub fn set_verify_writes(&mut self, verify_writes: bool) {
+        self.verify_writes = verify_writes;
+    }
+
+    /// Appends an instruction at the end of the rules
+    pub fn push(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+    }
+
     pub fn new(expressions: Vec<(Variable,ExpressionEvaluator)>) -> RulesEvaluator {
-        RulesEvaluator { expressions: expressions }
+        let instructions = expressions.into_iter().map(|(variable, expression)| {
+            Instruction::Assignment(variable, expression)
+        }).collect();
+        RulesEvaluator {
+            instructions: instructions,
+            verify_writes: false,
+        }
+    }
+}
+
+// Name of the variable as written in the rules
+fn source_name(variable: &Variable) -> String {
+    if variable.local {
+        variable.name.clone()
+    } else {
+        format!("${}", variable.name)
+    }
+}
+
+// Whether the instruction is a label, with the given name if any
+fn is_label(instruction: &Instruction, name: Option<&str>) -> bool {
+    match *instruction {
+        Instruction::Label(ref label) => name.map_or(true, |name| label == name),
+        _ => false,
+    }
+}
+
+// Global variables on top of read-only inputs
+struct WithInputs<'a> {
+    layers: Layered<'a>,
+    inputs: &'a Store,
+}
+
+impl <'a> Store for WithInputs<'a> {
+    fn get_attribute(&self, var: &str) -> Option<f64> {
+        self.layers.get_attribute(var)
+    }
+
+    fn set_attribute(&mut self, var: &str, value: f64) -> Result<Option<f64>,()> {
+        if self.inputs.get_attribute(var).is_some() {
+            return Err(());
+        }
+        self.layers.set_attribute(var, value)
+    }
+
+    fn get_history(&self, var: &str, steps_back: usize) -> Option<f64> {
+        self.layers.get_history(var, steps_back)
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.layers.names()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    use {parse_rule,ParseError};
+    use expressions::{Store,ExpressionError};
+    use super::RulesError;
+
+    // Only keeps one decimal
+    struct QuantizedStore(HashMap<String,f64>);
+
+    impl Store for QuantizedStore {
+        fn get_attribute(&self, var: &str) -> Option<f64> {
+            self.0.get(var).cloned()
+        }
+
+        fn set_attribute(&mut self, var: &str, value: f64) -> Result<Option<f64>,()> {
+            Ok(self.0.insert(var.into(), (value * 10.0).round() / 10.0))
+        }
+    }
+
+    #[test]
+    fn rename_variable() {
+        let mut rules = parse_rule("hp = 5; $hp = $hp - hp; $half = $hp / 2;").unwrap();
+        rules.rename_variable("hp", "health", false);
+        let mut global = HashMap::new();
+        global.insert("health".to_string(), 25.0);
+        rules.evaluate(&mut global).unwrap();
+        assert_eq!(global.get("health"), Some(&20.0));
+        assert_eq!(global.get("half"), Some(&10.0));
+        assert!(global.get("hp").is_none());
+    }
+
+    #[test]
+    fn previous_result() {
+        let mut global = HashMap::new();
+        parse_rule("$a = 2 + 3; $b = ans * 2; $c = ans + $a;").unwrap().evaluate(&mut global).unwrap();
+        assert_eq!(global.get("b"), Some(&10.0));
+        assert_eq!(global.get("c"), Some(&15.0));
+        assert!(parse_rule("$c = ans;").unwrap().evaluate(&mut global).is_err());
+        assert_eq!(parse_rule("ans = 1; $b = 2; $c = ans;").unwrap_err(),
+                   ParseError::Syntax("'ans' is a reserved keyword".into(), Some((0, 3))));
+        assert!(parse_rule("a = ans = 1;").is_err());
+        assert!(parse_rule("ans ?= 1;").is_err());
+        assert!(parse_rule("cycle(ans, 3);").is_err());
+        // Only the local variable is reserved
+        parse_rule("$ans = 1; $b = 2; $c = $ans;").unwrap().evaluate(&mut global).unwrap();
+        assert_eq!(global.get("c"), Some(&1.0));
+    }
+
+    #[test]
+    fn verify_writes() {
+        let mut rules = parse_rule("$a = 0.5; $b = 1 / 3;").unwrap();
+        let mut store = QuantizedStore(HashMap::new());
+        rules.evaluate(&mut store).unwrap();
+        assert_eq!(store.0.get("b"), Some(&0.3));
+
+        rules.set_verify_writes(true);
+        match rules.evaluate(&mut store) {
+            Err(RulesError::WriteMismatch { ref name, read: Some(read), .. }) => {
+                assert_eq!(name, "b");
+                assert_eq!(read, 0.3);
+            }
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dry_run() {
+        let rules = parse_rule("$hp = $hp - 5; $dead = 0; $hp = $hp * 2;").unwrap();
+        let mut global = HashMap::new();
+        global.insert("hp".to_string(), 20.0);
+        let changes = rules.dry_run(&global).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes.get("hp"), Some(&30.0));
+        assert_eq!(changes.get("dead"), Some(&0.0));
+        assert_eq!(global.len(), 1);
+        assert_eq!(global.get("hp"), Some(&20.0));
+    }
+
+    #[test]
+    fn trailing_expression() {
+        let mut global = HashMap::new();
+        let rules = parse_rule("a = 2; $b = 3; a + $b;").unwrap();
+        assert_eq!(rules.evaluate(&mut global).unwrap(), Some(5.0));
+        assert_eq!(parse_rule("2 + 3; ans * 2;").unwrap().evaluate(&mut global).unwrap(), Some(10.0));
+        assert_eq!(parse_rule("1; $c = 2;").unwrap().evaluate(&mut global).unwrap(), None);
+    }
+
+    #[test]
+    fn evaluate_label() {
+        let rules = parse_rule("@setup: $a = 1; $b = 2; @update: $a = $a + 10; $c = 3;").unwrap();
+        let mut global = HashMap::new();
+        rules.evaluate_label(&mut global, "setup").unwrap();
+        assert_eq!(global.get("a"), Some(&1.0));
+        assert_eq!(global.get("b"), Some(&2.0));
+        assert_eq!(global.get("c"), None);
+        rules.evaluate_label(&mut global, "update").unwrap();
+        assert_eq!(global.get("a"), Some(&11.0));
+        assert_eq!(global.get("c"), Some(&3.0));
+        match rules.evaluate_label(&mut global, "cleanup") {
+            Err(RulesError::UnknownLabel(ref label)) if label == "cleanup" => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn evaluate_with_locals() {
+        let rules = parse_rule("x ?= 1; x = x + 1; $a = x * 10;").unwrap();
+        let mut locals = HashMap::new();
+        for _ in 0..3 {
+            let mut global = HashMap::new();
+            rules.evaluate_with_locals(&mut global, &mut locals).unwrap();
+            // Locals of the previous evaluation must not leak into the next one
+            assert_eq!(global.get("a"), Some(&20.0));
+            assert_eq!(locals.get("x"), Some(&2.0));
+        }
+        let capacity = locals.capacity();
+        rules.evaluate_with_locals(&mut HashMap::new(), &mut locals).unwrap();
+        assert_eq!(locals.capacity(), capacity);
+    }
+
+    #[test]
+    fn evaluate_transactional() {
+        let mut global = HashMap::new();
+        global.insert("a".to_string(), 0.0);
+        let failing = parse_rule("$a = 1; $b = 2; $c = missing; $d = 4;").unwrap();
+        assert!(failing.evaluate_transactional(&mut global).is_err());
+        let mut expected = HashMap::new();
+        expected.insert("a".to_string(), 0.0);
+        assert_eq!(global, expected);
+        let succeeding = parse_rule("$a = 1; $b = 2;").unwrap();
+        succeeding.evaluate_transactional(&mut global).unwrap();
+        assert_eq!(global.get("a"), Some(&1.0));
+        assert_eq!(global.get("b"), Some(&2.0));
+    }
+
+    #[test]
+    fn evaluate_all() {
+        let rules = parse_rule("$a = missing; $b = 2; $c = other * 2; $d = $a + 1;").unwrap();
+        let mut global = HashMap::new();
+        let errors = rules.evaluate_all(&mut global);
+        let failed: Vec<usize> = errors.iter().map(|&(index, _)| index).collect();
+        // $d reads $a, which could not be set
+        assert_eq!(failed, vec![0, 2, 3]);
+        match errors[1].1 {
+            RulesError::Expression(ExpressionError::VariableNotFound(ref name)) => assert_eq!(name, "other"),
+            ref other => panic!("Unexpected error {:?}", other),
+        }
+        assert_eq!(global.get("b"), Some(&2.0));
+        assert_eq!(global.get("a"), None);
+        assert!(parse_rule("$a = 1;").unwrap().evaluate_all(&mut global).is_empty());
+    }
+
+    #[test]
+    fn evaluate_with_inputs() {
+        let mut inputs = HashMap::new();
+        inputs.insert("dt".to_string(), 0.5);
+        let mut global = HashMap::new();
+        global.insert("speed".to_string(), 4.0);
+        let rules = parse_rule("$distance = $speed * $dt;").unwrap();
+        rules.evaluate_with_inputs(&mut global, &inputs).unwrap();
+        assert_eq!(global.get("distance"), Some(&2.0));
+        assert_eq!(global.get("dt"), None);
+        match parse_rule("$dt = 1;").unwrap().evaluate_with_inputs(&mut global, &inputs) {
+            Err(RulesError::CannotSetVariable(ref name)) if name == "dt" => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert_eq!(inputs.get("dt"), Some(&0.5));
+    }
+
+    #[test]
+    fn evaluate_with_deadline() {
+        use std::time::{Duration, Instant};
+        let rules = parse_rule("$a = 1; $b = $a + 1; $b;").unwrap();
+        let mut global = HashMap::new();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(rules.evaluate_with_deadline(&mut global, deadline).unwrap(), Some(2.0));
+        let mut global = HashMap::new();
+        match rules.evaluate_with_deadline(&mut global, Instant::now()) {
+            Err(RulesError::Timeout) => {}
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(global.is_empty());
+    }
+
+    #[test]
+    fn error_display() {
+        let mut global = HashMap::new();
+        let error = parse_rule("$a = b * 2;").unwrap().evaluate(&mut global).unwrap_err();
+        let error: Box<Error> = Box::new(error);
+        assert_eq!(error.to_string(), "Variable b not found");
+        assert_eq!(error.source().unwrap().to_string(), "Variable b not found");
+    }
+
+    #[test]
+    fn dependency_edges() {
+        let rules = parse_rule("b = a + 1; c = b * 2; $d = c + prev($d, 1) + c;").unwrap();
+        assert_eq!(rules.dependency_edges(), vec![
+            ("b".to_string(), "a".to_string()),
+            ("c".to_string(), "b".to_string()),
+            ("$d".to_string(), "c".to_string()),
+            ("$d".to_string(), "$d".to_string()),
+            ]);
+        let rules = parse_rule("$total = sum_where(\"gold_\") + $bonus;").unwrap();
+        assert_eq!(rules.dependency_edges(), vec![
+            ("$total".to_string(), "$gold_*".to_string()),
+            ("$total".to_string(), "$bonus".to_string()),
+            ]);
+    }
+
+    #[test]
+    fn assignment_if_unset() {
+        let rules = parse_rule("$x ?= 5; y = 3; y ?= 4; $y = y;").unwrap();
+        let mut global = HashMap::new();
+        rules.evaluate(&mut global).unwrap();
+        assert_eq!(global.get("x"), Some(&5.0));
+        assert_eq!(global.get("y"), Some(&3.0));
+        global.insert("x".to_string(), 3.0);
+        rules.evaluate(&mut global).unwrap();
+        assert_eq!(global.get("x"), Some(&3.0));
     }
 }